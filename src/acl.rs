@@ -0,0 +1,295 @@
+//! Access control lists deciding which frontends may reach an fqdn.
+//!
+//! A frontend is identified by the token it sends with its connect request,
+//! the sha256 fingerprint of the client certificate it presents in the QUIC
+//! handshake, and its source address. An fqdn without any rule is open to
+//! everyone; once it has rules, a frontend must match one of them.
+//!
+//! The rendezvous server and the backend check the list before a connect
+//! is brokered, the backend again when it accepts the QUIC connection,
+//! taking the token only from the first stream of the session rather than
+//! from the relayed request. Fingerprints are only known after the
+//! handshake, so rules on them are assumed to match until then.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use ring::digest;
+
+/// An address range like `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.contains(&IpAddr::V4(ip)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// A bare address is a range of one.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("bad address {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| format!("bad prefix {}", s))?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(format!("bad prefix {}", s));
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Who a rule lets in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Principal {
+    Token(String),
+    /// Lowercase hex sha256 of the DER client certificate.
+    Fingerprint(String),
+    Cidr(Cidr),
+}
+
+impl FromStr for Principal {
+    type Err = String;
+
+    /// `token:<token>`, `cert:<sha256 hex>` or a cidr.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(token) = s.strip_prefix("token:") {
+            return Ok(Principal::Token(token.to_string()));
+        }
+        if let Some(fingerprint) = s.strip_prefix("cert:") {
+            let fingerprint = fingerprint.replace(':', "").to_lowercase();
+            if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("bad certificate fingerprint {}", s));
+            }
+            return Ok(Principal::Fingerprint(fingerprint));
+        }
+        Ok(Principal::Cidr(s.parse()?))
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::Token(token) => write!(f, "token:{}", token),
+            Principal::Fingerprint(fingerprint) => write!(f, "cert:{}", fingerprint),
+            Principal::Cidr(cidr) => write!(f, "{}", cidr),
+        }
+    }
+}
+
+/// What is known about a frontend at the time of a check.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub addr: IpAddr,
+    pub token: Option<String>,
+    /// `None` until the QUIC handshake is done, or if the frontend
+    /// presented no certificate.
+    pub fingerprint: Option<String>,
+}
+
+impl Identity {
+    pub fn new(addr: IpAddr, token: &str) -> Self {
+        Identity {
+            addr,
+            token: Some(token.to_string()).filter(|t| !t.is_empty()),
+            fingerprint: None,
+        }
+    }
+}
+
+/// Rules per fqdn, `*` applies to every fqdn.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    rules: HashMap<String, Vec<Principal>>,
+}
+
+impl Acl {
+    pub fn allow(mut self, fqdn: &str, principal: Principal) -> Self {
+        self.rules
+            .entry(fqdn.to_string())
+            .or_default()
+            .push(principal);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Parse rules given as principals per fqdn, see [`Principal::from_str`].
+    pub fn from_rules(rules: &HashMap<String, Vec<String>>) -> Result<Acl, String> {
+        let mut acl = Acl::default();
+        for (fqdn, principals) in rules.iter() {
+            for principal in principals.iter() {
+                acl = acl.allow(fqdn, principal.parse()?);
+            }
+        }
+        Ok(acl)
+    }
+
+    /// The rules in the form [`from_rules`](Self::from_rules) reads.
+    pub fn rules(&self) -> HashMap<String, Vec<String>> {
+        self.rules
+            .iter()
+            .map(|(fqdn, p)| (fqdn.clone(), p.iter().map(|p| p.to_string()).collect()))
+            .collect()
+    }
+
+    fn principals<'a>(&'a self, fqdn: &str) -> Option<impl Iterator<Item = &'a Principal>> {
+        let exact = self.rules.get(fqdn);
+        let any = self.rules.get("*");
+        if exact.is_none() && any.is_none() {
+            return None;
+        }
+        Some(exact.into_iter().chain(any).flatten())
+    }
+
+    /// Whether rules on `fqdn` may only be met with a client certificate,
+    /// which the backend then has to ask for.
+    pub fn wants_certificate(&self, fqdn: &str) -> bool {
+        self.principals(fqdn)
+            .is_some_and(|mut p| p.any(|p| matches!(p, Principal::Fingerprint(_))))
+    }
+
+    /// Check before the handshake, fingerprint rules pass.
+    pub fn admits(&self, fqdn: &str, identity: &Identity) -> bool {
+        self.check(fqdn, identity, true)
+    }
+
+    /// Check once the handshake is done.
+    pub fn allows(&self, fqdn: &str, identity: &Identity) -> bool {
+        self.check(fqdn, identity, false)
+    }
+
+    fn check(&self, fqdn: &str, identity: &Identity, early: bool) -> bool {
+        let Some(mut principals) = self.principals(fqdn) else {
+            return true;
+        };
+        principals.any(|p| match p {
+            Principal::Token(token) => identity.token.as_ref() == Some(token),
+            Principal::Fingerprint(fingerprint) => match identity.fingerprint.as_ref() {
+                Some(seen) => seen == fingerprint,
+                None => early,
+            },
+            Principal::Cidr(cidr) => cidr.contains(&identity.addr),
+        })
+    }
+}
+
+/// The fingerprint a DER certificate is listed with.
+pub fn fingerprint(der: &[u8]) -> String {
+    digest::digest(&digest::SHA256, der)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("192.168.1.7").to_string(), "192.168.1.7/32");
+        assert_eq!(cidr("2001:db8::/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net = cidr("10.1.0.0/16");
+        assert!(net.contains(&ip("10.1.255.3")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+
+        let net = cidr("2001:db8::/32");
+        assert!(net.contains(&ip("2001:db8:1::5")));
+        assert!(!net.contains(&ip("2001:db9::5")));
+
+        assert!(cidr("192.168.1.7").contains(&ip("192.168.1.7")));
+        assert!(!cidr("192.168.1.7").contains(&ip("192.168.1.8")));
+    }
+
+    #[test]
+    fn cidr_zero_prefix() {
+        assert!(cidr("0.0.0.0/0").contains(&ip("203.0.113.9")));
+        assert!(cidr("::/0").contains(&ip("2001:db8::1")));
+        // an IPv6 range does not cover plain IPv4 addresses
+        assert!(!cidr("::/0").contains(&ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn cidr_v4_mapped() {
+        let net = cidr("10.0.0.0/8");
+        assert!(net.contains(&ip("::ffff:10.9.8.7")));
+        assert!(!net.contains(&ip("::ffff:11.9.8.7")));
+        assert!(cidr("0.0.0.0/0").contains(&ip("::ffff:1.2.3.4")));
+        assert!(!net.contains(&ip("2001:db8::a00:1")));
+    }
+
+    #[test]
+    fn rules_round_trip() {
+        let rules = HashMap::from([(
+            "example.com".to_string(),
+            vec![
+                "token:secret".to_string(),
+                format!("cert:{}", "ab".repeat(32)),
+                "10.0.0.0/8".to_string(),
+            ],
+        )]);
+        let acl = Acl::from_rules(&rules).unwrap();
+        assert_eq!(acl.rules(), rules);
+        assert!(Acl::from_rules(&HashMap::from([(
+            "example.com".to_string(),
+            vec!["cert:abc".to_string()]
+        )]))
+        .is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Admin HTTP API of the rendezvous server.
+//!
+//! - `GET /backends`: registered fqdns with their backend addresses
+//! - `DELETE /backends/:fqdn[/:addr]`: evict registrations
+//! - `GET /attempts`: recent connect attempts and their outcome
+//! - `GET /bans`, `PUT|DELETE /bans/fqdn/:fqdn`, `PUT|DELETE /bans/ip/:ip`
+//! - `GET /metrics`: prometheus metrics, see [`crate::metrics`]
+//! - `GET /connections`: open tunnels with live byte and packet counters
+//!
+//! With a token configured, every route needs it as an `Authorization:
+//! Bearer` header, since even the listings name frontends and backends.
+//! Without one the API only listens on loopback addresses.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, put};
+use axum::{Json, Router};
+use ring::constant_time;
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::server::{Attempt, Registry};
+use crate::{metrics, net};
+
+type Shared = Arc<Mutex<Registry>>;
+
+#[derive(Serialize)]
+struct Backend {
+    fqdn: String,
+    addr: String,
+    /// The cluster member the backend is registered with, if not this one.
+    via: Option<SocketAddr>,
+    /// Unix time of the last registration or keepalive in seconds.
+    last_seen: u64,
+    /// Seconds since `last_seen`.
+    age: u64,
+}
+
+#[derive(Serialize)]
+struct Bans {
+    fqdns: Vec<String>,
+    ips: Vec<IpAddr>,
+}
+
+#[derive(Serialize)]
+struct Evicted {
+    evicted: usize,
+}
+
+/// Bind the admin API on `laddr`, which has to be a loopback address
+/// unless the API is protected by a token.
+pub fn listen(laddr: SocketAddr, token: Option<&str>) -> io::Result<TcpListener> {
+    if token.is_none() && !laddr.ip().is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("admin api on {} needs a token", laddr),
+        ));
+    }
+    let listener = net::tcp_listen(laddr)?;
+    info!("admin api listen on {}", laddr);
+    Ok(listener)
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    registry: Shared,
+    token: Option<String>,
+) -> io::Result<()> {
+    let token: Option<Arc<str>> = token.map(Arc::from);
+    let app = Router::new()
+        .route("/backends", get(backends))
+        .route("/backends/:fqdn", delete(evict_fqdn))
+        .route("/backends/:fqdn/:addr", delete(evict_addr))
+        .route("/attempts", get(attempts))
+        .route("/bans", get(bans))
+        .route("/bans/fqdn/:fqdn", put(ban_fqdn).delete(unban_fqdn))
+        .route("/bans/ip/:ip", put(ban_ip).delete(unban_ip))
+        .route("/metrics", get(metrics::handler))
+        .route("/connections", get(metrics::connections))
+        .route_layer(middleware::from_fn_with_state(token, authorize))
+        .with_state(registry);
+    axum::serve(listener, app).await
+}
+
+/// Only let requests through that carry `token` as bearer token, if set.
+async fn authorize(State(token): State<Option<Arc<str>>>, req: Request, next: Next) -> Response {
+    if let Some(token) = token {
+        let given = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes()).is_err() {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+    next.run(req).await
+}
+
+async fn backends(State(registry): State<Shared>) -> Json<Vec<Backend>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let registry = registry.lock().unwrap();
+    let mut list = Vec::new();
+    for (fqdn, bs) in registry.backends.iter() {
+        for (addr, reg) in bs.iter() {
+            list.push(Backend {
+                fqdn: fqdn.clone(),
+                addr: addr.clone(),
+                via: reg.via,
+                last_seen: reg.seen.as_secs(),
+                age: now.saturating_sub(reg.seen).as_secs(),
+            });
+        }
+    }
+    list.sort_by(|a, b| (&a.fqdn, &a.addr).cmp(&(&b.fqdn, &b.addr)));
+    Json(list)
+}
+
+async fn evict_fqdn(State(registry): State<Shared>, Path(fqdn): Path<String>) -> Json<Evicted> {
+    let evicted = registry.lock().unwrap().evict(&fqdn, None);
+    Json(Evicted { evicted })
+}
+
+async fn evict_addr(
+    State(registry): State<Shared>,
+    Path((fqdn, addr)): Path<(String, String)>,
+) -> Json<Evicted> {
+    let evicted = registry.lock().unwrap().evict(&fqdn, Some(&addr));
+    Json(Evicted { evicted })
+}
+
+async fn attempts(State(registry): State<Shared>) -> Json<Vec<Attempt>> {
+    let registry = registry.lock().unwrap();
+    Json(registry.attempts.iter().rev().cloned().collect())
+}
+
+async fn bans(State(registry): State<Shared>) -> Json<Bans> {
+    let registry = registry.lock().unwrap();
+    let mut fqdns: Vec<String> = registry.banned_fqdns.iter().cloned().collect();
+    let mut ips: Vec<IpAddr> = registry.banned_ips.iter().cloned().collect();
+    fqdns.sort();
+    ips.sort();
+    Json(Bans { fqdns, ips })
+}
+
+async fn ban_fqdn(State(registry): State<Shared>, Path(fqdn): Path<String>) -> StatusCode {
+    registry.lock().unwrap().ban_fqdn(&fqdn);
+    StatusCode::NO_CONTENT
+}
+
+async fn unban_fqdn(State(registry): State<Shared>, Path(fqdn): Path<String>) -> StatusCode {
+    match registry.lock().unwrap().unban_fqdn(&fqdn) {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn ban_ip(State(registry): State<Shared>, Path(ip): Path<IpAddr>) -> StatusCode {
+    registry.lock().unwrap().ban_ip(ip);
+    StatusCode::NO_CONTENT
+}
+
+async fn unban_ip(State(registry): State<Shared>, Path(ip): Path<IpAddr>) -> StatusCode {
+    match registry.lock().unwrap().unban_ip(ip) {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    }
+}
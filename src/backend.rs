@@ -1,109 +1,625 @@
+use s2n_quic::connection::Handle;
+use s2n_quic::provider::endpoint_limits::{ConnectionAttempt, Limiter, Outcome};
 use s2n_quic::provider::io::tokio::Builder as IOBuilder;
-use s2n_quic::Server;
+use s2n_quic::stream::BidirectionalStream;
+use s2n_quic::{Connection, Server};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::io;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tracing::{debug, debug_span, error, info, info_span, instrument, warn, Instrument};
 
-use crate::endpoint::Kind;
+use crate::acl::{Acl, Identity};
+use crate::endpoint::{Kind, Timers};
+use crate::layer::iobound::compress::{Codec, Compressed};
+use crate::layer::iobound::header::StreamHeader;
+use crate::layer::iobound::shape::Shaper;
+use crate::layer::iobound::tunnel::Timeouts;
+use crate::limit::CookieJar;
 use crate::message::{self, ConnMessage, Message, StunMessage};
-use crate::tunnel;
+use crate::tls::{self, mtls};
+use crate::tunnel::proxy_protocol;
+use crate::{metrics, net, tunnel};
+use tunnel::udp;
 
 pub struct Backend {
     fqdn: String,
     laddr: SocketAddr,
-    stun_addr: String,
+    /// Rendezvous servers, the next one is used when the current one stops
+    /// answering.
+    stun_addrs: Vec<String>,
+    udp_raddr: Option<SocketAddr>,
+    /// Udp flows one frontend may have open at a time.
+    udp_flows: usize,
+    reverse: Vec<(SocketAddr, String)>,
+    /// Codecs offered on the streams of each reverse service.
+    compression: HashMap<String, Vec<Codec>>,
+    timers: Timers,
+    acl: Arc<Acl>,
+    timeouts: Timeouts,
+    shaper: Arc<Shaper>,
+    proxy_protocol: Option<proxy_protocol::Version>,
+    metrics: Option<SocketAddr>,
+}
+
+/// Connections of the frontends currently attached, newest last. Each
+/// punched session runs its own quic server, so connection ids can repeat
+/// and peers are told apart by a process wide counter instead.
+type Peers = Arc<Mutex<Vec<(u64, Handle)>>>;
+
+static NEXT_PEER: AtomicU64 = AtomicU64::new(0);
+
+/// Application error code a connection the acl denies is closed with.
+const ACL_DENIED: u32 = 0x403;
+
+/// How long a frontend has to send its token once connected.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lets only the frontend a socket was punched for start handshakes, one
+/// at a time, and forgets the certificate of the previous handshake when a
+/// new one starts.
+struct OnePeer {
+    frontend: Vec<SocketAddr>,
+    seen: mtls::Seen,
+}
+
+impl Limiter for OnePeer {
+    fn on_connection_attempt(&mut self, info: &ConnectionAttempt) -> Outcome {
+        let remote = net::canonical(SocketAddr::from(&info.remote_address));
+        if !self.frontend.contains(&remote) || info.inflight_handshakes > 0 {
+            return Outcome::drop();
+        }
+        *self.seen.lock().unwrap() = None;
+        Outcome::allow()
+    }
+}
+
+/// What a punched session needs from the backend configuration.
+#[derive(Clone)]
+struct Session {
+    fqdn: String,
+    laddr: SocketAddr,
+    udp_raddr: Option<SocketAddr>,
+    udp_flows: usize,
+    peers: Peers,
+    timers: Timers,
+    acl: Arc<Acl>,
+    timeouts: Timeouts,
+    shaper: Arc<Shaper>,
+    proxy_protocol: Option<proxy_protocol::Version>,
 }
 
 impl Backend {
+    /// `stun_addr` may list several clustered rendezvous servers separated
+    /// by commas.
     pub fn new(fqdn: &str, laddr: SocketAddr, stun_addr: &str) -> Self {
         return Backend {
             fqdn: fqdn.to_string(),
             laddr: laddr,
-            stun_addr: stun_addr.to_string(),
+            stun_addrs: net::split_list(stun_addr),
+            udp_raddr: None,
+            udp_flows: udp::MAX_FLOWS,
+            reverse: Vec::new(),
+            compression: HashMap::new(),
+            timers: Timers::default(),
+            acl: Arc::new(Acl::default()),
+            timeouts: Timeouts::default(),
+            shaper: Arc::new(Shaper::default()),
+            proxy_protocol: None,
+            metrics: None,
         };
     }
 
+    pub fn with_timers(mut self, timers: Timers) -> Self {
+        self.timers = timers;
+        self
+    }
+
+    /// Only let in frontends the acl allows, checked when the rendezvous
+    /// server announces them and again on QUIC accept.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Arc::new(acl);
+        self
+    }
+
+    /// Idle, lifetime and connect limits of the forwarded connections.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Bandwidth limits per service (the fqdn or a reverse service), per
+    /// frontend address and overall.
+    pub fn with_shaper(mut self, shaper: Shaper) -> Self {
+        self.shaper = Arc::new(shaper);
+        self
+    }
+
+    /// Tell the service at `laddr` the address of each frontend's client
+    /// with a PROXY protocol header of `version`.
+    pub fn with_proxy_protocol(mut self, version: proxy_protocol::Version) -> Self {
+        self.proxy_protocol = Some(version);
+        self
+    }
+
+    /// Serve Prometheus metrics on `laddr`.
+    pub fn with_metrics(mut self, laddr: SocketAddr) -> Self {
+        self.metrics = Some(laddr);
+        self
+    }
+
+    /// Listen on `laddr` and forward each connection to `service` on the
+    /// frontend side, see [`Frontend::with_service`](crate::Frontend::with_service).
+    pub fn with_reverse(mut self, laddr: SocketAddr, service: &str) -> Self {
+        self.reverse.push((laddr, service.to_string()));
+        self
+    }
+
+    /// Offer `codecs` to the frontend for the streams of the reverse
+    /// `service`, see [`Compressed`].
+    pub fn with_compression(mut self, service: &str, codecs: &[Codec]) -> Self {
+        self.compression
+            .insert(service.to_string(), codecs.to_vec());
+        self
+    }
+
+    /// Also deliver udp from frontends to the service at `raddr`.
+    pub fn with_udp(mut self, raddr: SocketAddr) -> Self {
+        self.udp_raddr = Some(raddr);
+        self
+    }
+
+    /// Let each frontend have at most `max` udp flows open at a time,
+    /// datagrams starting another one are dropped.
+    pub fn with_udp_flows(mut self, max: usize) -> Self {
+        self.udp_flows = max;
+        self
+    }
+
     pub async fn run(self) -> Result<(), Box<dyn Error>> {
-        let laddr = self.laddr.clone();
-        let stun_addr = self.stun_addr.clone();
+        let laddr = self.laddr;
         let fqdn = self.fqdn.clone();
-        while let Ok(socket) = Self::fetch(stun_addr.clone(), fqdn.clone()).await {
+        let udp_raddr = self.udp_raddr;
+        let udp_flows = self.udp_flows;
+        if let Some(laddr) = self.metrics {
+            metrics::spawn(laddr);
+        }
+        let peers: Peers = Arc::new(Mutex::new(Vec::new()));
+        let timeouts = self.timeouts;
+        for (laddr, service) in self.reverse {
+            let peers = peers.clone();
+            let shaper = self.shaper.clone();
+            let offer = self.compression.get(&service).cloned().unwrap_or_default();
             tokio::spawn(async move {
-                _ = Self::handle(socket, laddr.clone()).await;
+                let res = Self::reverse(laddr, service, offer, peers, timeouts, shaper).await;
+                if let Err(err) = res {
+                    error!("reverse tunnel listen on {} error: {}", laddr, err);
+                }
             });
         }
-
-        Ok(())
+        if self.stun_addrs.is_empty() {
+            return Err("no rendezvous server configured".into());
+        }
+        let mut next = 0;
+        loop {
+            let stun_addr = &self.stun_addrs[next % self.stun_addrs.len()];
+            next += 1;
+            let session = Session {
+                fqdn: fqdn.clone(),
+                laddr,
+                udp_raddr,
+                udp_flows,
+                peers: peers.clone(),
+                timers: self.timers.clone(),
+                acl: self.acl.clone(),
+                timeouts,
+                shaper: self.shaper.clone(),
+                proxy_protocol: self.proxy_protocol,
+            };
+            if let Err(err) = Self::control(stun_addr, session).await {
+                warn!(
+                    "control session with {} error: {}, reconnecting",
+                    stun_addr, err
+                );
+            }
+            tokio::time::sleep(self.timers.retransmit).await;
+        }
     }
 
-    pub async fn fetch(stun_addr: String, fqdn: String) -> Result<UdpSocket, Box<dyn Error>> {
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        let stun_addr = stun_addr;
-        let fqdn = fqdn;
+    /// Keep a control session with the rendezvous server: register, send
+    /// keepalives so the registration and the NAT binding stay fresh, and
+    /// start a punching socket for every frontend the server announces.
+    /// Returns once the server stops answering.
+    #[instrument(skip(session), fields(fqdn = %session.fqdn))]
+    async fn control(stun_addr: &str, session: Session) -> Result<(), Box<dyn Error>> {
+        let socket = net::udp_bind_any()?;
+        let stun_addr = net::resolve_from(&socket.local_addr()?, stun_addr).await?;
 
-        let msg = StunMessage::new(Kind::Backend, fqdn);
-        let data = msg.encode()?;
+        let mut msg = StunMessage::new(Kind::Backend, session.fqdn.clone());
+        // a placeholder cookie makes the first registration as large as the
+        // server's cookie challenge, which it will not answer otherwise
+        msg.cookie = vec![0; CookieJar::LEN];
+        let mut data = msg.clone().encode()?;
+        let keepalive = session.timers.keepalive;
+        let mut ticker = tokio::time::interval(keepalive);
+        // frontends a punching socket is already being set up for
+        let inflight: Arc<Mutex<HashSet<SocketAddr>>> = Arc::new(Mutex::new(HashSet::new()));
+        let mut last_seen = Instant::now();
         let mut buf = [0; 1500];
         loop {
-            let data = data.clone();
-            _ = socket.send_to(&data, stun_addr.clone()).await?;
-
-            tokio::time::sleep(Duration::from_secs(2)).await;
-
-            if let Ok((n, raddr)) = socket.try_recv_from(&mut buf) {
-                let msg = message::decode(&buf[..n]);
-                match msg {
-                    Message::Stun(msg) => {
-                        println!("recv stun message {} from: {}", msg, raddr.to_string(),);
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if last_seen.elapsed() > keepalive * 3 {
+                        return Err(format!("no answer from {}", stun_addr).into());
                     }
-                    Message::Conn(msg) => {
-                        println!("recv conn message {} from {}", msg, raddr.to_string());
-
-                        let target_addr = msg.raddr.clone();
-                        let msg = ConnMessage::new(Kind::Backend, socket.local_addr()?, msg.fqdn);
-                        let data = msg.encode()?;
-                        _ = socket.send_to(&data, target_addr).await?;
-                        return Ok(socket);
+                    _ = socket.send_to(&data, stun_addr).await?;
+                }
+                res = socket.recv_from(&mut buf) => {
+                    let (n, raddr) = res?;
+                    if net::canonical(raddr) != net::canonical(stun_addr) {
+                        continue;
                     }
-                    Message::Unknown(data) => {
-                        println!("reccv unknown msg {:?}", data);
+                    last_seen = Instant::now();
+                    match message::decode(&buf[..n]) {
+                        // a challenge, or the registration answered with
+                        // the cookie for the next keepalives
+                        Message::Stun(reply) if !reply.cookie.is_empty() => {
+                            if reply.cookie != msg.cookie {
+                                msg.cookie = reply.cookie;
+                                data = msg.clone().encode()?;
+                                _ = socket.send_to(&data, stun_addr).await?;
+                            }
+                        }
+                        Message::Stun(_) => {}
+                        Message::Conn(msg) => {
+                            debug!("recv conn message {} from {}", msg, raddr);
+                            let identity = Identity::new(msg.raddr.ip(), &msg.token);
+                            if !session.acl.admits(&session.fqdn, &identity) {
+                                info!(peer = %msg.raddr, "frontend denied by acl");
+                                continue;
+                            }
+                            // the frontend repeats its request until answered
+                            if !inflight.lock().unwrap().insert(msg.raddr) {
+                                continue;
+                            }
+                            let session = session.clone();
+                            let inflight = inflight.clone();
+                            let span = info_span!("session", peer = %msg.raddr);
+                            tokio::spawn(async move {
+                                let frontend = msg.raddr;
+                                let res = Self::fetch(stun_addr, msg, &session.timers)
+                                    .await
+                                    .map_err(|e| e.to_string());
+                                inflight.lock().unwrap().remove(&frontend);
+                                let (socket, frontend) = match res {
+                                    Ok(punched) => punched,
+                                    Err(err) => {
+                                        warn!("punch error: {}", err);
+                                        return;
+                                    }
+                                };
+                                let res = Self::handle(socket, frontend, session).await;
+                                if let Err(err) = res {
+                                    warn!("quic server error: {}", err);
+                                }
+                            }.instrument(span));
+                        }
+                        Message::Peer(msg) => {
+                            debug!("recv unexpected peer msg {}", msg);
+                        }
+                        Message::Unknown(data) => {
+                            debug!("recv unknown msg {:?}", data);
+                        }
                     }
                 }
             }
         }
     }
-    pub async fn handle(socket: UdpSocket, laddr: SocketAddr) -> Result<(), Box<dyn Error>> {
+
+    /// Bind a fresh socket for the frontend announced in `msg`, report it to
+    /// the rendezvous server and punch toward the frontend, retrying with
+    /// backoff until the frontend's punch arrives. Only punches from the
+    /// addresses the server relayed count. Returns the socket and those
+    /// addresses, the ones the frontend may connect from.
+    pub async fn fetch(
+        stun_addr: SocketAddr,
+        msg: ConnMessage,
+        timers: &Timers,
+    ) -> Result<(UdpSocket, Vec<SocketAddr>), Box<dyn Error>> {
+        let socket = net::udp_bind_any()?;
+        let local = socket.local_addr()?;
+        let stun_addr = net::reachable_from(&local, stun_addr);
+
+        // tells the server which frontend this socket is for, the server
+        // passes the address it sees on to that frontend once the nonce
+        // proves the request reached us
+        let mut ready = ConnMessage::new(Kind::Backend, msg.raddr, msg.fqdn.clone());
+        ready.candidates = net::candidates(&socket);
+        ready.nonce = msg.nonce.clone();
+        let ready = ready.encode()?;
+
+        let targets = net::punch_targets(msg.raddr, &msg.candidates);
+        let frontend = msg.raddr;
+        let msg = ConnMessage::new(Kind::Backend, local, msg.fqdn);
+        let data = msg.encode()?;
+
+        let started = Instant::now();
+        metrics::global()
+            .punch_attempts
+            .with_label_values(&["backend"])
+            .inc();
+        let deadline = started + timers.timeout;
+        let mut delay = timers.retransmit;
+        let mut buf = [0; 1500];
+        while Instant::now() < deadline {
+            _ = socket.send_to(&ready, stun_addr).await?;
+            for target in targets.iter() {
+                let target = net::reachable_from(&local, *target);
+                if let Err(err) = socket.send_to(&data, target).await {
+                    debug!("send conn message to {} error: {}", target, err);
+                }
+            }
+            let next = (Instant::now() + delay).min(deadline);
+            delay = timers.backoff(delay);
+            while let Ok(res) = tokio::time::timeout_at(next, socket.recv_from(&mut buf)).await {
+                let (n, raddr) = res?;
+                if !targets.contains(&net::canonical(raddr)) {
+                    debug!(peer = %raddr, "punch from an address the server did not relay");
+                    continue;
+                }
+                let punched = match message::decode(&buf[..n]) {
+                    Message::Conn(msg) => msg.kind == Kind::Frontend,
+                    // the frontend may already be sending quic
+                    _ => true,
+                };
+                if punched {
+                    // answer so the frontend stops punching too
+                    _ = socket.send_to(&data, raddr).await;
+                    metrics::global().punched("backend", started.elapsed());
+                    return Ok((socket, targets));
+                }
+            }
+        }
+        Err(format!("no answer from frontend {}", frontend).into())
+    }
+
+    /// Read the token a frontend sends on the first stream it opens, see
+    /// [`Frontend::listen`](crate::frontend::Frontend::listen).
+    async fn session_token(connection: &mut Connection) -> Option<String> {
+        let read = async {
+            let Some(mut stream) = connection.accept_bidirectional_stream().await? else {
+                return Ok(None);
+            };
+            io::Result::Ok(StreamHeader::read_from(&mut stream).await?.token)
+        };
+        match tokio::time::timeout(TOKEN_TIMEOUT, read).await {
+            Ok(Ok(token)) => token,
+            Ok(Err(err)) => {
+                debug!("read frontend token error: {}", err);
+                None
+            }
+            Err(_) => {
+                debug!("no token from frontend in time");
+                None
+            }
+        }
+    }
+
+    /// Serve QUIC on a punched socket for the frontend at the addresses in
+    /// `frontend`.
+    async fn handle(
+        socket: UdpSocket,
+        frontend: Vec<SocketAddr>,
+        session: Session,
+    ) -> Result<(), Box<dyn Error>> {
+        let Session {
+            fqdn,
+            laddr,
+            udp_raddr,
+            udp_flows,
+            peers,
+            acl,
+            timeouts,
+            shaper,
+            proxy_protocol,
+            ..
+        } = session;
         let tx = socket.into_std()?;
         let rx = tx.try_clone()?;
 
-        let tls = s2n_quic::provider::tls::default::Server::builder()
-            .with_certificate(Path::new("quic.crt"), Path::new("quic.key"))?
-            .build()?;
         let socket_io = IOBuilder::default()
             .with_tx_socket(tx)?
             .with_rx_socket(rx)?
             .build()?;
-        println!("recv conn from frontend, start listen quic ...");
-        let mut server = Server::builder()
-            .with_tls(tls)?
+        debug!("recv conn from frontend, start listen quic");
+        // each punched socket serves a single frontend, and only one
+        // handshake from it at a time, so the certificate its server sees
+        // is that of the handshake in flight
+        let seen: mtls::Seen = Arc::new(Mutex::new(None));
+        let limits = OnePeer {
+            frontend: frontend.clone(),
+            seen: seen.clone(),
+        };
+        let builder = Server::builder()
             .with_io(socket_io)?
-            .start()?;
+            .with_endpoint_limits(limits)?;
+        let mut server = if acl.wants_certificate(&fqdn) {
+            let tls = mtls::fingerprint_server_tls("quic.crt", "quic.key", seen.clone())?;
+            builder
+                .with_tls(tls)?
+                .with_datagram(udp::endpoint()?)?
+                .start()?
+        } else {
+            let tls = s2n_quic::provider::tls::default::Server::builder()
+                .with_certificate(Path::new("quic.crt"), Path::new("quic.key"))?
+                .with_application_protocols(tls::ALPN.iter())?
+                .build()?;
+            builder
+                .with_tls(tls)?
+                .with_datagram(udp::endpoint()?)?
+                .start()?
+        };
 
-        println!("quic server started, accept msg ...");
+        debug!("quic server started");
         while let Some(mut connection) = server.accept().await {
+            let remote = connection.remote_addr()?;
+            let fingerprint = seen.lock().unwrap().take();
+            if !frontend.contains(&net::canonical(remote)) {
+                info!(peer = %remote, "connection from another address than the frontend");
+                connection.close(ACL_DENIED.into());
+                continue;
+            }
+            let mut identity = Identity {
+                addr: net::canonical(remote).ip(),
+                token: None,
+                fingerprint,
+            };
+            // frontends from before stream headers send the client's bytes
+            // right away on forward streams
+            let headers = connection
+                .application_protocol()
+                .is_ok_and(|p| p.as_ref() == tls::ALPN_STREAM_HEADER);
             // spawn a new task for the connection
-            _ = tokio::spawn(async move {
-                println!("Connection accepted from {:?}", connection.remote_addr());
+            let peers = peers.clone();
+            let fqdn = fqdn.clone();
+            let acl = acl.clone();
+            let shaper = shaper.clone();
+            let span = info_span!("connection", peer = %remote);
+            _ = tokio::spawn(
+                async move {
+                    // the token the server relayed is only the frontend's
+                    // word, it counts once sent inside the session
+                    if !acl.allows(&fqdn, &identity) && headers {
+                        identity.token = Self::session_token(&mut connection).await;
+                    }
+                    if !acl.allows(&fqdn, &identity) {
+                        info!("connection denied by acl");
+                        connection.close(ACL_DENIED.into());
+                        return;
+                    }
+                    info!("connection accepted");
+                    let _active = metrics::connection("backend");
+                    if let Some(udp_raddr) = udp_raddr {
+                        let handle = connection.handle();
+                        tokio::spawn(
+                            async move {
+                                let idle = udp::IDLE_TIMEOUT;
+                                let res =
+                                    udp::backward_datagrams(udp_raddr, handle, idle, udp_flows)
+                                        .await;
+                                if let Err(err) = res {
+                                    warn!("udp forward error: {}", err);
+                                }
+                            }
+                            .in_current_span(),
+                        );
+                    }
 
-                while let Ok(Some(stream)) = connection.accept_bidirectional_stream().await {
-                    _ = tokio::spawn(async move {
-                        _ = tunnel::backward_tunnel(laddr.clone(), stream).await;
-                    });
+                    let id = NEXT_PEER.fetch_add(1, Ordering::Relaxed);
+                    peers.lock().unwrap().push((id, connection.handle()));
+                    while let Ok(Some(stream)) = connection.accept_bidirectional_stream().await {
+                        let span = debug_span!("stream", id = stream.id());
+                        let fqdn = fqdn.clone();
+                        let limit = shaper.limit(&fqdn, Some(identity.addr));
+                        _ = tokio::spawn(
+                            async move {
+                                let _active = metrics::stream("backend");
+                                let res = tunnel::backward_tunnel(
+                                    &fqdn,
+                                    laddr,
+                                    stream,
+                                    timeouts,
+                                    limit,
+                                    proxy_protocol,
+                                    headers,
+                                )
+                                .await;
+                                if let Err(err) = res {
+                                    debug!("backward tunnel error: {}", err);
+                                }
+                            }
+                            .instrument(span),
+                        );
+                    }
+                    peers.lock().unwrap().retain(|(peer, _)| *peer != id);
+                    info!("connection closed");
                 }
-            });
+                .instrument(span),
+            );
         }
         Ok(())
     }
+
+    /// Accept connections on `laddr` and open a stream for each toward
+    /// `service` over the newest frontend connection.
+    async fn reverse(
+        laddr: SocketAddr,
+        service: String,
+        offer: Vec<Codec>,
+        peers: Peers,
+        timeouts: Timeouts,
+        shaper: Arc<Shaper>,
+    ) -> io::Result<()> {
+        let lis = net::tcp_listen(laddr)?;
+        info!("reverse tunnel {} listen on {}", service, laddr);
+        loop {
+            let (tcp_stream, _raddr) = lis.accept().await?;
+            let peers = peers.clone();
+            let service = service.clone();
+            let offer = offer.clone();
+            let limit = shaper.limit(&service, None);
+            tokio::spawn(async move {
+                match Self::open_reverse(&peers, &service, &offer).await {
+                    Ok(quic_stream) => {
+                        let _active = metrics::stream("backend");
+                        _ = tunnel::forward_tunnel(
+                            &service,
+                            tcp_stream,
+                            quic_stream,
+                            timeouts,
+                            limit,
+                        )
+                        .await;
+                    }
+                    Err(err) => {
+                        warn!("reverse tunnel {} error: {}", service, err);
+                    }
+                }
+            });
+        }
+    }
+
+    async fn open_reverse(
+        peers: &Peers,
+        service: &str,
+        offer: &[Codec],
+    ) -> io::Result<Compressed<BidirectionalStream>> {
+        loop {
+            let handle = peers.lock().unwrap().last().cloned();
+            let Some((id, mut handle)) = handle else {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "no frontend connected",
+                ));
+            };
+            match handle.open_bidirectional_stream().await {
+                Ok(stream) => {
+                    let header = StreamHeader::new().with_service(service);
+                    let reopen = || async move { Ok(handle.open_bidirectional_stream().await?) };
+                    return Compressed::open_or_raw(stream, reopen, header, offer).await;
+                }
+                Err(err) => {
+                    // the connection is gone, try an older one
+                    debug!("open reverse stream error: {}", err);
+                    peers.lock().unwrap().retain(|(peer, _)| *peer != id);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,577 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::acl::Acl;
+use crate::endpoint::Timers;
+use crate::layer::iobound::compress::Codec;
+use crate::layer::iobound::shape::{Priority, Shaper};
+use crate::layer::iobound::tunnel::Timeouts;
+use crate::layer::iobound::{http, quicin, quicout, socks5, tcpin, tcpout};
+use crate::layer::{Proxy, SharedOutBound};
+use crate::limit::{Limits, Rate};
+use crate::log::LogConfig;
+use crate::pool::{PreDial, PreDialConfig};
+use crate::tunnel::proxy_protocol;
+use crate::{Backend, Frontend, StunServer};
+
+/// Top level of `config.yaml`.
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub proxies: Vec<ProxyConfig>,
+    #[serde(default)]
+    pub log: LogConfig,
+    /// Serve Prometheus metrics on this address.
+    #[serde(default)]
+    pub metrics: Option<SocketAddr>,
+    /// Bandwidth limits shared by all proxies.
+    #[serde(default)]
+    pub shaping: ShapingSettings,
+    /// The rendezvous server, run with `--stun`.
+    #[serde(default)]
+    pub server: Option<ServerConfig>,
+    /// The backend, run with `-b`.
+    #[serde(default)]
+    pub backend: Option<BackendConfig>,
+    /// The frontend, run with `-f`.
+    #[serde(default)]
+    pub frontend: Option<FrontendConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ServerConfig {
+    pub listen: String,
+    /// Serve the admin API on this address.
+    pub admin: Option<SocketAddr>,
+    /// Bearer token for the admin API, needed off loopback.
+    pub admin_token: Option<String>,
+    /// Keep registrations, bans and acls in this file.
+    pub store: Option<String>,
+    /// Other members of the cluster.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Shared by every member of the cluster.
+    pub peer_secret: Option<String>,
+    #[serde(default)]
+    pub limits: LimitSettings,
+    /// Principals allowed per fqdn, see [`Acl::from_rules`].
+    #[serde(default)]
+    pub acl: HashMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BackendConfig {
+    pub fqdn: String,
+    /// The local service frontends are connected to.
+    pub target: SocketAddr,
+    /// Rendezvous servers, separated by commas.
+    pub stun: String,
+    #[serde(default)]
+    pub timers: TimerSettings,
+    #[serde(default)]
+    pub timeouts: TimeoutSettings,
+    /// Principals allowed per fqdn, see [`Acl::from_rules`].
+    #[serde(default)]
+    pub acl: HashMap<String, Vec<String>>,
+    /// Announce frontend clients to `target` with a PROXY protocol header.
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+    /// Local listeners tunneled to services on the frontend side.
+    #[serde(default)]
+    pub reverse: Vec<ReverseConfig>,
+    /// Deliver udp from frontends to this address.
+    pub udp: Option<SocketAddr>,
+    /// Udp flows one frontend may have open at a time.
+    pub udp_flows: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReverseConfig {
+    pub listen: SocketAddr,
+    /// The service name the frontend maps to a target.
+    pub service: String,
+    /// Codecs offered for each stream, in order of preference.
+    #[serde(default)]
+    pub compression: Vec<Codec>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FrontendConfig {
+    pub fqdn: String,
+    /// Where local clients connect.
+    pub listen: SocketAddr,
+    /// Address of the punching socket, any address of the host if unset.
+    pub bind: Option<SocketAddr>,
+    /// Rendezvous servers, separated by commas.
+    pub stun: String,
+    #[serde(default)]
+    pub timers: TimerSettings,
+    #[serde(default)]
+    pub timeouts: TimeoutSettings,
+    /// Sent to the acls of the rendezvous server and the backend.
+    pub token: Option<String>,
+    /// Client certificate and key presented to the backend.
+    pub identity: Option<IdentitySettings>,
+    /// Services the backend may open reverse streams to, mapped to
+    /// `host:port`.
+    #[serde(default)]
+    pub services: HashMap<String, String>,
+    /// Codecs offered for each forward stream, in order of preference.
+    #[serde(default)]
+    pub compression: Vec<Codec>,
+    /// Forward udp received on this address to the backend.
+    pub udp: Option<SocketAddr>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IdentitySettings {
+    pub cert: String,
+    pub key: String,
+}
+
+/// One inbound listener and the outbound its connections are sent to.
+#[derive(Deserialize, Debug)]
+pub struct ProxyConfig {
+    pub inbound: InBoundConfig,
+    pub outbound: OutBoundConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutSettings,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum InBoundConfig {
+    Tcp {
+        listen: SocketAddr,
+    },
+    Http {
+        listen: SocketAddr,
+    },
+    Socks5 {
+        listen: SocketAddr,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// QUIC tunnel server on a public udp port.
+    Quic {
+        listen: SocketAddr,
+        #[serde(default = "default_cert")]
+        cert: String,
+        #[serde(default = "default_key")]
+        key: String,
+        /// Service names clients may ask for, mapped to `host:port`.
+        #[serde(default)]
+        services: HashMap<String, String>,
+        /// Secret clients have to send with every stream; required unless
+        /// the server listens on a loopback address.
+        token: Option<String>,
+        /// Let clients name any target, not only those of `services`.
+        #[serde(default)]
+        allow_targets: bool,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum OutBoundConfig {
+    Tcp {
+        target: Option<SocketAddr>,
+        predial: Option<PreDialSettings>,
+    },
+    /// QUIC tunnel client dialing a `quic` inbound at `server`.
+    Quic {
+        server: String,
+        #[serde(default = "default_server_name")]
+        server_name: String,
+        #[serde(default = "default_cert")]
+        cert: String,
+        /// Skip checking that the server certificate is issued for
+        /// `server_name`.
+        #[serde(default)]
+        insecure: bool,
+        /// Secret the server requires with every stream.
+        token: Option<String>,
+        predial: Option<PreDialSettings>,
+        /// Codecs offered for each stream, in order of preference.
+        #[serde(default)]
+        compression: Vec<Codec>,
+    },
+}
+
+fn default_cert() -> String {
+    "quic.crt".to_string()
+}
+
+fn default_key() -> String {
+    "quic.key".to_string()
+}
+
+fn default_server_name() -> String {
+    "localhost".to_string()
+}
+
+/// How many connections of an outbound are dialed ahead of time,
+/// durations in seconds.
+#[derive(Deserialize, Debug)]
+pub struct PreDialSettings {
+    pub min_idle: Option<usize>,
+    pub max_idle: Option<usize>,
+    pub max_lifetime: Option<u64>,
+    pub check_interval: Option<u64>,
+}
+
+impl PreDialSettings {
+    pub fn config(&self) -> PreDialConfig {
+        let mut config = PreDialConfig::default();
+        if let Some(min_idle) = self.min_idle {
+            config.min_idle = min_idle;
+        }
+        if let Some(max_idle) = self.max_idle {
+            config.max_idle = max_idle;
+        }
+        if let Some(secs) = self.max_lifetime {
+            config.max_lifetime = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.check_interval {
+            config.check_interval = Duration::from_secs(secs);
+        }
+        config
+    }
+}
+
+/// Limits on forwarded connections in seconds, `0` disables a limit and a
+/// missing one keeps its default.
+#[derive(Deserialize, Debug, Default)]
+pub struct TimeoutSettings {
+    pub idle: Option<u64>,
+    pub lifetime: Option<u64>,
+    pub connect: Option<u64>,
+}
+
+impl TimeoutSettings {
+    pub fn timeouts(&self) -> Timeouts {
+        let secs = |s: u64| Some(Duration::from_secs(s)).filter(|d| !d.is_zero());
+        let mut timeouts = Timeouts::default();
+        if let Some(s) = self.idle {
+            timeouts.idle = secs(s);
+        }
+        if let Some(s) = self.lifetime {
+            timeouts.lifetime = secs(s);
+        }
+        if let Some(s) = self.connect {
+            timeouts.connect = secs(s);
+        }
+        timeouts
+    }
+}
+
+/// Bandwidth limits in bytes per second over both directions, `0` disables
+/// a limit. Services are named by the listen address of a tcp, http or
+/// socks5 inbound, or by the service a quic client asks for.
+#[derive(Deserialize, Debug, Default)]
+pub struct ShapingSettings {
+    pub global: Option<u64>,
+    /// Per client address.
+    pub per_client: Option<u64>,
+    #[serde(default)]
+    pub services: HashMap<String, u64>,
+    #[serde(default)]
+    pub priorities: HashMap<String, Priority>,
+}
+
+impl ShapingSettings {
+    pub fn shaper(&self) -> Shaper {
+        // a second's worth of burst, at least a full read buffer
+        let rate = |bytes: u64| Rate::new(bytes as f64, bytes.max(64 * 1024) as f64);
+        let mut shaper = Shaper::default();
+        if let Some(bytes) = self.global.filter(|b| *b > 0) {
+            shaper = shaper.with_global(rate(bytes));
+        }
+        if let Some(bytes) = self.per_client.filter(|b| *b > 0) {
+            shaper = shaper.with_frontend(rate(bytes));
+        }
+        for (service, bytes) in self.services.iter().filter(|(_, b)| **b > 0) {
+            shaper = shaper.with_service(service, rate(*bytes));
+        }
+        for (service, priority) in self.priorities.iter() {
+            shaper = shaper.with_priority(service, *priority);
+        }
+        shaper
+    }
+}
+
+/// Rendezvous and punching timers in milliseconds, a missing one keeps
+/// its default.
+#[derive(Deserialize, Debug, Default)]
+pub struct TimerSettings {
+    pub retransmit: Option<u64>,
+    pub max_retransmit: Option<u64>,
+    pub timeout: Option<u64>,
+    pub v6_grace: Option<u64>,
+    pub keepalive: Option<u64>,
+}
+
+impl TimerSettings {
+    pub fn timers(&self) -> Timers {
+        let mut timers = Timers::default();
+        let fields = [
+            (self.retransmit, &mut timers.retransmit),
+            (self.max_retransmit, &mut timers.max_retransmit),
+            (self.timeout, &mut timers.timeout),
+            (self.v6_grace, &mut timers.v6_grace),
+            (self.keepalive, &mut timers.keepalive),
+        ];
+        for (ms, timer) in fields {
+            if let Some(ms) = ms {
+                *timer = Duration::from_millis(ms);
+            }
+        }
+        timers
+    }
+}
+
+/// Rate limits of the rendezvous server in messages per second, a missing
+/// one keeps its default.
+#[derive(Deserialize, Debug, Default)]
+pub struct LimitSettings {
+    pub per_ip: Option<f64>,
+    pub per_ip_burst: Option<f64>,
+    pub per_fqdn: Option<f64>,
+    pub per_fqdn_burst: Option<f64>,
+    /// Largest reply in bytes.
+    pub max_response: Option<usize>,
+}
+
+impl LimitSettings {
+    pub fn limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        let rate = |rate: Rate, per_sec: Option<f64>, burst: Option<f64>| {
+            Rate::new(per_sec.unwrap_or(rate.per_sec), burst.unwrap_or(rate.burst))
+        };
+        limits.per_ip = rate(limits.per_ip, self.per_ip, self.per_ip_burst);
+        limits.per_fqdn = rate(limits.per_fqdn, self.per_fqdn, self.per_fqdn_burst);
+        if let Some(max_response) = self.max_response {
+            limits.max_response = max_response;
+        }
+        limits
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        if data.trim().is_empty() {
+            return Ok(Config::default());
+        }
+        Ok(serde_yaml::from_str(&data)?)
+    }
+
+    /// The rendezvous server of the `server` section.
+    pub fn server(&self) -> Result<StunServer, Box<dyn Error>> {
+        let cfg = self.server.as_ref().ok_or("config has no server section")?;
+        let mut server = StunServer::new(&cfg.listen)
+            .with_limits(cfg.limits.limits())
+            .with_acl(Acl::from_rules(&cfg.acl)?);
+        if let Some(laddr) = cfg.admin {
+            server = server.with_admin(laddr);
+        }
+        if let Some(token) = cfg.admin_token.as_ref() {
+            server = server.with_admin_token(token);
+        }
+        if let Some(path) = cfg.store.as_ref() {
+            server = server.with_store(path);
+        }
+        for peer in cfg.peers.iter() {
+            server = server.with_peer(peer);
+        }
+        if let Some(secret) = cfg.peer_secret.as_ref() {
+            server = server.with_peer_secret(secret);
+        }
+        if let Some(laddr) = self.metrics {
+            server = server.with_metrics(laddr);
+        }
+        Ok(server)
+    }
+
+    /// The backend of the `backend` section.
+    pub fn backend(&self) -> Result<Backend, Box<dyn Error>> {
+        let cfg = self
+            .backend
+            .as_ref()
+            .ok_or("config has no backend section")?;
+        let mut backend = Backend::new(&cfg.fqdn, cfg.target, &cfg.stun)
+            .with_timers(cfg.timers.timers())
+            .with_timeouts(cfg.timeouts.timeouts())
+            .with_shaper(self.shaping.shaper())
+            .with_acl(Acl::from_rules(&cfg.acl)?);
+        if let Some(version) = cfg.proxy_protocol {
+            backend = backend.with_proxy_protocol(version);
+        }
+        for reverse in cfg.reverse.iter() {
+            backend = backend
+                .with_reverse(reverse.listen, &reverse.service)
+                .with_compression(&reverse.service, &reverse.compression);
+        }
+        if let Some(raddr) = cfg.udp {
+            backend = backend.with_udp(raddr);
+        }
+        if let Some(max) = cfg.udp_flows {
+            backend = backend.with_udp_flows(max);
+        }
+        if let Some(laddr) = self.metrics {
+            backend = backend.with_metrics(laddr);
+        }
+        Ok(backend)
+    }
+
+    /// The frontend of the `frontend` section.
+    pub fn frontend(&self) -> Result<Frontend, Box<dyn Error>> {
+        let cfg = self
+            .frontend
+            .as_ref()
+            .ok_or("config has no frontend section")?;
+        let mut frontend = Frontend::new(&cfg.fqdn, &cfg.listen.to_string(), &cfg.stun)
+            .with_timers(cfg.timers.timers())
+            .with_timeouts(cfg.timeouts.timeouts())
+            .with_shaper(self.shaping.shaper())
+            .with_compression(&cfg.fqdn, &cfg.compression);
+        if let Some(laddr) = cfg.bind {
+            frontend = frontend.with_bind(laddr);
+        }
+        if let Some(token) = cfg.token.as_ref() {
+            frontend = frontend.with_token(token);
+        }
+        if let Some(identity) = cfg.identity.as_ref() {
+            frontend = frontend.with_identity(&identity.cert, &identity.key);
+        }
+        for (name, target) in cfg.services.iter() {
+            frontend = frontend.with_service(name, target.parse()?);
+        }
+        if let Some(laddr) = cfg.udp {
+            frontend = frontend.with_udp(laddr);
+        }
+        if let Some(laddr) = self.metrics {
+            frontend = frontend.with_metrics(laddr);
+        }
+        Ok(frontend)
+    }
+
+    /// Pair every configured inbound with its outbound.
+    pub fn proxy(&self) -> Result<Proxy, Box<dyn Error>> {
+        let mut proxy = Proxy::new();
+        let shaper = Arc::new(self.shaping.shaper());
+        for cfg in self.proxies.iter() {
+            let timeouts = cfg.timeouts.timeouts();
+            let out = cfg.outbound.build(timeouts);
+            proxy = match &cfg.inbound {
+                // a tcp inbound names no target, the outbound has to
+                InBoundConfig::Tcp { listen }
+                    if matches!(cfg.outbound, OutBoundConfig::Tcp { target: None, .. }) =>
+                {
+                    return Err(format!("tcp inbound {} needs an outbound target", listen).into());
+                }
+                InBoundConfig::Tcp { listen } => proxy.with_inbound(
+                    tcpin::TcpProxy::new(out, *listen)?
+                        .with_timeouts(timeouts)
+                        .with_shaper(shaper.clone()),
+                ),
+                InBoundConfig::Http { listen } => proxy.with_inbound(
+                    http::TcpProxy::new(out, *listen)?
+                        .with_timeouts(timeouts)
+                        .with_shaper(shaper.clone()),
+                ),
+                InBoundConfig::Socks5 {
+                    listen,
+                    username,
+                    password,
+                } => {
+                    let mut inbound = socks5::Socks5Proxy::new(out, *listen)?
+                        .with_timeouts(timeouts)
+                        .with_shaper(shaper.clone());
+                    match (username, password) {
+                        (Some(username), Some(password)) => {
+                            inbound = inbound.with_auth(username, password);
+                        }
+                        (None, None) => {}
+                        _ => {
+                            return Err(format!(
+                                "socks5 inbound {} needs both a username and a password",
+                                listen
+                            )
+                            .into());
+                        }
+                    }
+                    proxy.with_inbound(inbound)
+                }
+                InBoundConfig::Quic {
+                    listen,
+                    cert,
+                    key,
+                    services,
+                    token,
+                    allow_targets,
+                } => {
+                    let mut inbound = quicin::QuicServer::new(out, *listen, cert, key)?
+                        .with_timeouts(timeouts)
+                        .with_shaper(shaper.clone());
+                    for (name, target) in services.iter() {
+                        inbound = inbound.with_service(name, target.parse()?);
+                    }
+                    if let Some(token) = token {
+                        inbound = inbound.with_token(token);
+                    }
+                    if *allow_targets {
+                        inbound = inbound.with_allow_targets();
+                    }
+                    proxy.with_inbound(inbound)
+                }
+            };
+        }
+        Ok(proxy)
+    }
+}
+
+impl OutBoundConfig {
+    /// The outbound, dialing within `timeouts.connect` where it dials tcp.
+    pub fn build(&self, timeouts: Timeouts) -> SharedOutBound {
+        match self {
+            OutBoundConfig::Tcp { target, predial } => {
+                let out = tcpout::TcpOutStream::new(*target).with_timeouts(timeouts);
+                match predial {
+                    // only the default target can be dialed ahead of time
+                    Some(predial) if target.is_some() => {
+                        SharedOutBound::new(PreDial::new(out, predial.config()))
+                    }
+                    _ => SharedOutBound::new(out),
+                }
+            }
+            OutBoundConfig::Quic {
+                server,
+                server_name,
+                cert,
+                insecure,
+                token,
+                predial,
+                compression,
+            } => {
+                let mut out = quicout::QuicDialer::new(server, server_name, cert)
+                    .with_compression(compression);
+                if *insecure {
+                    out = out.with_insecure();
+                }
+                if let Some(token) = token {
+                    out = out.with_token(token);
+                }
+                match predial {
+                    Some(predial) => SharedOutBound::new(PreDial::new(out, predial.config())),
+                    None => SharedOutBound::new(out),
+                }
+            }
+        }
+    }
+}
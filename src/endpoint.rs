@@ -1,4 +1,5 @@
 use std::fmt::{Display, Formatter, Result};
+use std::time::Duration;
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Kind {
@@ -32,3 +33,38 @@ impl Kind {
         }
     }
 }
+
+/// Timing of the rendezvous and hole punching exchanges.
+#[derive(Clone, Debug)]
+pub struct Timers {
+    /// Delay before an unanswered message is first sent again, doubled
+    /// after every retry.
+    pub retransmit: Duration,
+    /// Upper bound of the retransmit delay.
+    pub max_retransmit: Duration,
+    /// How long an exchange is retried before giving up.
+    pub timeout: Duration,
+    /// How long a punch answered over IPv4 waits for an IPv6 answer.
+    pub v6_grace: Duration,
+    /// How often the backend control session sends keepalives.
+    pub keepalive: Duration,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Timers {
+            retransmit: Duration::from_millis(500),
+            max_retransmit: Duration::from_secs(4),
+            timeout: Duration::from_secs(30),
+            v6_grace: Duration::from_secs(3),
+            keepalive: Duration::from_secs(20),
+        }
+    }
+}
+
+impl Timers {
+    /// The retransmit delay to use after `delay`.
+    pub fn backoff(&self, delay: Duration) -> Duration {
+        (delay * 2).min(self.max_retransmit)
+    }
+}
@@ -1,105 +1,314 @@
+use crate::layer::iobound::compress::{Codec, Compressed};
+use crate::layer::iobound::header::StreamHeader;
+use crate::layer::iobound::shape::{Limit, Shaper};
+use crate::layer::iobound::target::Target;
+use crate::layer::iobound::tunnel::Timeouts;
+use crate::limit::CookieJar;
 use crate::message::Message;
-use crate::{endpoint, message, tunnel};
-use endpoint::Kind;
+use crate::{endpoint, message, metrics, net, tls, tunnel};
+use endpoint::{Kind, Timers};
 use message::{ConnMessage, StunMessage};
-use s2n_quic::connection::Connection;
+use s2n_quic::connection::{Connection, Handle};
 use s2n_quic::provider::io::tokio::Builder as IOBuilder;
 use s2n_quic::{client::Connect, Client};
+use std::collections::HashMap;
 use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tunnel::udp;
 
-use std::time::Duration;
-use tokio::net::{TcpListener, UdpSocket};
-use tokio::task::JoinHandle;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::Instant;
+use tracing::{debug, debug_span, field, info, instrument, warn, Instrument, Span};
 
 #[cfg(target_family = "windows")]
-pub use crate::tls::rustls::insecure_client_tls;
+pub use crate::tls::rustls::{client_tls, identity_client_tls, insecure_client_tls};
 #[cfg(target_family = "unix")]
-pub use crate::tls::s2ntls::insecure_client_tls;
+pub use crate::tls::s2ntls::{client_tls, identity_client_tls, insecure_client_tls};
 
 pub struct Frontend {
     fqdn: String,
     laddr: String,
-    stun_addr: String,
+    /// Rendezvous servers, tried in order until one answers.
+    stun_addrs: Vec<String>,
+    udp_laddr: Option<SocketAddr>,
+    /// Address of the punching socket, any address of the host if unset.
+    bind: Option<SocketAddr>,
+    services: HashMap<String, Target>,
+    /// Codecs offered on forward streams, by service.
+    compression: HashMap<String, Vec<Codec>>,
+    timers: Timers,
+    /// Sent with the connect request for the backend's acl.
+    token: String,
+    /// Client certificate and key presented to the backend.
+    identity: Option<(String, String)>,
+    timeouts: Timeouts,
+    shaper: Arc<Shaper>,
+    metrics: Option<SocketAddr>,
 }
 
 impl Frontend {
+    /// `stun_addr` may list several clustered rendezvous servers separated
+    /// by commas.
     pub fn new(fqdn: &str, laddr: &str, stun_addr: &str) -> Self {
         Frontend {
             fqdn: fqdn.to_string(),
             laddr: laddr.to_string(),
-            stun_addr: stun_addr.to_string(),
+            stun_addrs: net::split_list(stun_addr),
+            udp_laddr: None,
+            bind: None,
+            services: HashMap::new(),
+            compression: HashMap::new(),
+            timers: Timers::default(),
+            token: String::new(),
+            identity: None,
+            timeouts: Timeouts::default(),
+            shaper: Arc::new(Shaper::default()),
+            metrics: None,
         }
     }
 
-    pub async fn listen(self, mut quic_conn: Connection) -> Result<(), Box<dyn Error>> {
-        let lis = TcpListener::bind(self.laddr).await?;
-        loop {
-            let (tcp_stream, _raddr) = lis.accept().await?;
-            let quic_stream = quic_conn.open_bidirectional_stream().await?;
-            tunnel::forward_tunnel(tcp_stream, quic_stream).await?;
-        }
+    pub fn with_timers(mut self, timers: Timers) -> Self {
+        self.timers = timers;
+        self
     }
 
-    pub async fn run(self) -> Result<(), Box<dyn Error>> {
-        let laddr = self.laddr.clone();
-        let stun_addr = self.stun_addr.clone();
-        let fqdn = self.fqdn.clone();
+    /// Identify as `token` to the acls of the rendezvous server and the
+    /// backend, which takes it only once sent again inside the QUIC
+    /// session.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = token.to_string();
+        self
+    }
 
-        let socket: UdpSocket = UdpSocket::bind(laddr).await?;
+    /// Present the certificate at `cert` to the backend, whose acl may
+    /// list its fingerprint.
+    pub fn with_identity(mut self, cert: &str, key: &str) -> Self {
+        self.identity = Some((cert.to_string(), key.to_string()));
+        self
+    }
 
-        let msg = StunMessage::new(Kind::Frontend, fqdn.clone());
-        let data = msg.encode()?;
-        _ = socket.send_to(&data, stun_addr).await?;
-        println!("send connect msg, wait stun connection info");
+    /// Idle, lifetime and connect limits of the forwarded connections.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
 
-        let mut buf = [0; 1500];
-        let (n, raddr) = socket.recv_from(&mut buf).await?;
-        let mut msg = ConnMessage::default();
-        _ = msg.decode(&buf[..n])?;
-        println!("recv connect msg {} from {}", msg, raddr);
-
-        let target_addr = msg.raddr.clone();
-        let fqdn_clone = fqdn.clone();
-        let task: JoinHandle<Result<UdpSocket, std::io::Error>> = tokio::spawn(async move {
-            let laddr = socket.local_addr()?;
-            let msg = ConnMessage::new(Kind::Frontend, laddr, fqdn_clone);
-            let data = msg.encode().unwrap();
-            let mut buf = [0; 1500];
-            loop {
-                _ = socket.send_to(&data, target_addr).await?;
-                println!("send connect msg to {}", target_addr.to_string());
-                tokio::time::sleep(Duration::from_secs(2)).await;
-                match socket.try_recv_from(&mut buf) {
-                    Ok((n, raddr)) => match message::decode(&buf[..n]) {
-                        Message::Conn(msg) => match msg.kind {
-                            Kind::Backend => {
-                                return Ok(socket);
-                            }
-                            _ => {
-                                println!("recv msg {} from {}", msg, raddr,);
+    /// Bandwidth limits per service (the fqdn or a service the backend
+    /// asks for) and overall.
+    pub fn with_shaper(mut self, shaper: Shaper) -> Self {
+        self.shaper = Arc::new(shaper);
+        self
+    }
+
+    /// Serve Prometheus metrics on `laddr`.
+    pub fn with_metrics(mut self, laddr: SocketAddr) -> Self {
+        self.metrics = Some(laddr);
+        self
+    }
+
+    /// Let the backend open streams to `target` on this side by naming
+    /// `service`, see [`Backend::with_reverse`](crate::Backend::with_reverse).
+    pub fn with_service(mut self, service: &str, target: Target) -> Self {
+        self.services.insert(service.to_string(), target);
+        self
+    }
+
+    /// Offer `codecs` to the backend for the forward streams of `service`,
+    /// which is the fqdn, see [`Compressed`].
+    pub fn with_compression(mut self, service: &str, codecs: &[Codec]) -> Self {
+        self.compression
+            .insert(service.to_string(), codecs.to_vec());
+        self
+    }
+
+    /// Punch and talk QUIC from `laddr` instead of an ephemeral port on
+    /// any address. `laddr` has to reach the rendezvous server and the
+    /// backend, unlike the tcp listen address which may be loopback.
+    pub fn with_bind(mut self, laddr: SocketAddr) -> Self {
+        self.bind = Some(laddr);
+        self
+    }
+
+    /// Also forward udp received on `laddr` to the backend's udp service.
+    pub fn with_udp(mut self, laddr: SocketAddr) -> Self {
+        self.udp_laddr = Some(laddr);
+        self
+    }
+
+    pub async fn listen(self, quic_conn: Connection) -> Result<(), Box<dyn Error>> {
+        let lis = net::tcp_listen(self.laddr.parse()?)?;
+        let _active = metrics::connection("frontend");
+        // backends from before stream headers take the client's bytes right
+        // away on forward streams
+        let headers = quic_conn
+            .application_protocol()
+            .is_ok_and(|p| p.as_ref() == tls::ALPN_STREAM_HEADER);
+        let (handle, mut acceptor) = quic_conn.split();
+        // the backend takes the token only from inside the session, on the
+        // first stream, before it serves anything else
+        if headers && !self.token.is_empty() {
+            let mut stream = handle.clone().open_bidirectional_stream().await?;
+            let header = StreamHeader::new().with_token(&self.token);
+            header.write_to(&mut stream).await?;
+            stream.close().await?;
+        }
+        if let Some(udp_laddr) = self.udp_laddr {
+            let handle = handle.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(err) =
+                        udp::forward_datagrams(udp_laddr, handle, udp::IDLE_TIMEOUT).await
+                    {
+                        warn!("udp forward error: {}", err);
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+        let services = Arc::new(self.services);
+        let offer: Arc<[Codec]> = match headers {
+            true => self
+                .compression
+                .get(&self.fqdn)
+                .cloned()
+                .unwrap_or_default(),
+            false => Vec::new(),
+        }
+        .into();
+        let timeouts = self.timeouts;
+        let shaper = self.shaper.clone();
+        // ends once the connection is gone
+        let mut closed = tokio::spawn(
+            async move {
+                while let Ok(Some(stream)) = acceptor.accept_bidirectional_stream().await {
+                    let services = services.clone();
+                    let shaper = shaper.clone();
+                    let span = debug_span!("stream", id = stream.id());
+                    tokio::spawn(
+                        async move {
+                            let _active = metrics::stream("frontend");
+                            if let Err(err) =
+                                tunnel::service_tunnel(&services, stream, timeouts, &shaper).await
+                            {
+                                warn!("reverse tunnel error: {}", err);
                             }
-                        },
-                        Message::Stun(msg) => {
-                            println!("recv unexpected stun msg {}", msg);
-                        }
-                        Message::Unknown(data) => {
-                            println!("recv unknown msg {:?}", data);
-                        }
-                    },
-                    Err(e) => {
-                        if e.kind() as u8 == std::io::ErrorKind::WouldBlock as u8 {
-                            continue;
                         }
-                        println!("recv msg error {}", e.kind());
-                        return Err(e);
+                        .instrument(span),
+                    );
+                }
+            }
+            .in_current_span(),
+        );
+        loop {
+            let (tcp_stream, raddr) = tokio::select! {
+                res = lis.accept() => res?,
+                _ = &mut closed => return Err("quic connection closed".into()),
+            };
+            let handle = handle.clone();
+            let fqdn = self.fqdn.clone();
+            let offer = offer.clone();
+            let limit = self.shaper.limit(&self.fqdn, None);
+            let span = debug_span!("stream", id = field::Empty, client = %raddr);
+            tokio::spawn(
+                async move {
+                    let _active = metrics::stream("frontend");
+                    // a failed stream or tunnel must not end the session
+                    let res =
+                        Self::forward(&fqdn, handle, tcp_stream, headers, &offer, timeouts, limit)
+                            .await;
+                    if let Err(err) = res {
+                        debug!("forward tunnel error: {}", err);
                     }
                 }
+                .instrument(span),
+            );
+        }
+    }
+
+    /// Open a stream for the client on `tcp_stream` and carry its
+    /// connection over it. With `headers`, the stream header names the
+    /// client, which the backend may pass on to its service, and offers
+    /// the `offer` codecs.
+    async fn forward(
+        fqdn: &str,
+        mut handle: Handle,
+        tcp_stream: TcpStream,
+        headers: bool,
+        offer: &[Codec],
+        timeouts: Timeouts,
+        limit: Limit,
+    ) -> Result<(), Box<dyn Error>> {
+        let quic_stream = handle.open_bidirectional_stream().await?;
+        Span::current().record("id", field::display(quic_stream.id()));
+        let quic_stream = match headers {
+            true => {
+                let mut header = StreamHeader::new();
+                if let Ok(client) = tcp_stream.peer_addr() {
+                    header = header.with_client(client);
+                }
+                let reopen = || async move { Ok(handle.open_bidirectional_stream().await?) };
+                Compressed::open_or_raw(quic_stream, reopen, header, offer).await?
+            }
+            false => Compressed::Raw(quic_stream),
+        };
+        tunnel::forward_tunnel(fqdn, tcp_stream, quic_stream, timeouts, limit).await
+    }
+
+    #[instrument(skip_all, fields(fqdn = %self.fqdn, peer = field::Empty))]
+    pub async fn run(self) -> Result<(), Box<dyn Error>> {
+        let fqdn = self.fqdn.clone();
+        if let Some(laddr) = self.metrics {
+            metrics::spawn(laddr);
+        }
+
+        let socket: UdpSocket = match self.bind {
+            Some(laddr) => net::udp_bind(laddr)?,
+            None => net::udp_bind_any()?,
+        };
+        let local = socket.local_addr()?;
+
+        let mut msg = StunMessage::new(Kind::Frontend, fqdn.clone());
+        msg.candidates = net::candidates(&socket);
+        // a placeholder cookie makes the first request as large as the
+        // server's cookie challenge, which it will not answer otherwise
+        msg.cookie = vec![0; CookieJar::LEN];
+        msg.token = self.token.clone();
+        debug!("send connect msg, wait stun connection info");
+        let mut answer = None;
+        for server in self.stun_addrs.iter() {
+            let stun_addr = match net::resolve_from(&local, server).await {
+                Ok(stun_addr) => stun_addr,
+                Err(err) => {
+                    warn!("resolve {} error: {}", server, err);
+                    continue;
+                }
+            };
+            match Self::rendezvous(&socket, stun_addr, &msg, &self.timers).await {
+                Ok(msg) => {
+                    answer = Some((msg, stun_addr));
+                    break;
+                }
+                Err(err) => warn!("rendezvous with {} failed: {}", server, err),
             }
-        });
+        }
+        let Some((msg, stun_addr)) = answer else {
+            return Err("no rendezvous server answered".into());
+        };
+        debug!("recv connect msg {} from {}", msg, stun_addr);
 
-        let socket = task.await??;
+        let targets = net::punch_targets(msg.raddr, &msg.candidates);
+        let data = ConnMessage::new(Kind::Frontend, local, fqdn.clone()).encode()?;
+        let started = Instant::now();
+        metrics::global()
+            .punch_attempts
+            .with_label_values(&["frontend"])
+            .inc();
+        let target_addr = Self::punch(&socket, &targets, &data, &self.timers).await?;
+        metrics::global().punched("frontend", started.elapsed());
 
-        println!("start quic conn");
+        Span::current().record("peer", field::display(target_addr));
+        info!("punched, start quic conn");
 
         let tx_udp = socket.into_std()?;
         let rx_udp = tx_udp.try_clone()?;
@@ -108,12 +317,17 @@ impl Frontend {
             .with_tx_socket(tx_udp)?
             .with_rx_socket(rx_udp)?
             .build()?;
-        let tls = insecure_client_tls("quic.crt")?;
+        let tls = match &self.identity {
+            Some((cert, key)) => identity_client_tls("quic.crt", cert, key)?,
+            None => insecure_client_tls("quic.crt")?,
+        };
         let client = Client::builder()
             .with_tls(tls)?
             .with_io(socket_io)?
+            .with_datagram(udp::endpoint()?)?
             .start()?;
 
+        let target_addr = net::reachable_from(&local, target_addr);
         let connect = Connect::new(target_addr).with_server_name(fqdn.clone().as_str());
         let mut connection = client.connect(connect).await?;
         connection.keep_alive(true)?;
@@ -121,4 +335,118 @@ impl Frontend {
         self.listen(connection).await?;
         Ok(())
     }
+
+    /// Ask the rendezvous server for the backend of our fqdn, sending the
+    /// request again with backoff until the answer arrives. A cookie
+    /// challenge from the server is answered right away.
+    async fn rendezvous(
+        socket: &UdpSocket,
+        stun_addr: SocketAddr,
+        msg: &StunMessage,
+        timers: &Timers,
+    ) -> Result<ConnMessage, Box<dyn Error>> {
+        let mut req = msg.clone();
+        let mut data = req.clone().encode()?;
+        let deadline = Instant::now() + timers.timeout;
+        let mut delay = timers.retransmit;
+        let mut buf = [0; 1500];
+        while Instant::now() < deadline {
+            _ = socket.send_to(&data, stun_addr).await?;
+            let next = (Instant::now() + delay).min(deadline);
+            delay = timers.backoff(delay);
+            while let Ok(res) = tokio::time::timeout_at(next, socket.recv_from(&mut buf)).await {
+                let (n, raddr) = res?;
+                match message::decode(&buf[..n]) {
+                    Message::Conn(msg) if net::canonical(raddr) == net::canonical(stun_addr) => {
+                        return Ok(msg);
+                    }
+                    Message::Conn(msg) => {
+                        debug!("recv early conn msg {} from {}", msg, raddr);
+                    }
+                    Message::Stun(msg)
+                        if msg.kind == Kind::Stun
+                            && !msg.cookie.is_empty()
+                            && net::canonical(raddr) == net::canonical(stun_addr) =>
+                    {
+                        debug!("recv cookie from {}, send connect msg again", stun_addr);
+                        req.cookie = msg.cookie;
+                        data = req.clone().encode()?;
+                        _ = socket.send_to(&data, stun_addr).await?;
+                    }
+                    Message::Stun(msg) => {
+                        debug!("recv unexpected stun msg {}", msg);
+                    }
+                    Message::Peer(msg) => {
+                        debug!("recv unexpected peer msg {}", msg);
+                    }
+                    Message::Unknown(data) => {
+                        debug!("recv unknown msg {:?}", data);
+                    }
+                }
+            }
+        }
+        Err(format!("no answer from {}", stun_addr).into())
+    }
+
+    /// Send punch messages to every target with backoff until the backend
+    /// answers, and return the address it answered from. An IPv4 answer is
+    /// only used after giving the IPv6 path `v6_grace` more to answer.
+    async fn punch(
+        socket: &UdpSocket,
+        targets: &[SocketAddr],
+        data: &[u8],
+        timers: &Timers,
+    ) -> Result<SocketAddr, Box<dyn Error>> {
+        let local = socket.local_addr()?;
+        let want_v6 = targets.first().is_some_and(|target| target.is_ipv6());
+        let mut fallback: Option<SocketAddr> = None;
+        let mut deadline = Instant::now() + timers.timeout;
+        let mut delay = timers.retransmit;
+        let mut buf = [0; 1500];
+        while Instant::now() < deadline {
+            for target in targets.iter() {
+                let target = net::reachable_from(&local, *target);
+                if let Err(err) = socket.send_to(data, target).await {
+                    debug!("send connect msg to {} error: {}", target, err);
+                    continue;
+                }
+                debug!("send connect msg to {}", target);
+            }
+            let next = (Instant::now() + delay).min(deadline);
+            delay = timers.backoff(delay);
+            while let Ok(res) = tokio::time::timeout_at(next, socket.recv_from(&mut buf)).await {
+                let (n, raddr) = res?;
+                match message::decode(&buf[..n]) {
+                    Message::Conn(msg) => match msg.kind {
+                        Kind::Backend => {
+                            let raddr = net::canonical(raddr);
+                            if raddr.is_ipv6() || !want_v6 {
+                                return Ok(raddr);
+                            }
+                            if fallback.is_none() {
+                                fallback = Some(raddr);
+                                deadline = deadline.min(Instant::now() + timers.v6_grace);
+                            }
+                        }
+                        _ => {
+                            debug!("recv msg {} from {}", msg, raddr);
+                        }
+                    },
+                    Message::Stun(msg) => {
+                        debug!("recv unexpected stun msg {}", msg);
+                    }
+                    Message::Peer(msg) => {
+                        debug!("recv unexpected peer msg {}", msg);
+                    }
+                    Message::Unknown(data) => {
+                        debug!("recv unknown msg {:?}", data);
+                    }
+                }
+            }
+        }
+        match fallback {
+            Some(addr) => Ok(addr),
+            None => Err("no answer from backend".into()),
+        }
+    }
 }
@@ -0,0 +1,239 @@
+//! Per-stream compression of tunnel streams, negotiated in the
+//! [`StreamHeader`].
+//!
+//! The opening side lists the codecs it is willing to use; the accepting
+//! side answers with a header naming the first one it supports, or naming
+//! none, in which case both sides carry the stream raw. Only streams that
+//! offer compression get an answer. Headers that offer it carry a newer
+//! header version, which peers from before compression reject by dropping
+//! the stream; [`Compressed::open_or_raw`] then carries the stream raw on a
+//! fresh one.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use async_compression::tokio::bufread::{Lz4Decoder, ZstdDecoder};
+use async_compression::tokio::write::{Lz4Encoder, ZstdEncoder};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader, ReadBuf, ReadHalf, WriteHalf};
+use tracing::debug;
+
+use super::header::StreamHeader;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    /// Every codec this build supports, in order of preference.
+    pub const ALL: &'static [Codec] = &[Codec::Zstd, Codec::Lz4];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Codec {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Codec::Zstd),
+            "lz4" => Ok(Codec::Lz4),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown codec {}", s),
+            )),
+        }
+    }
+}
+
+type Reader = Pin<Box<dyn AsyncRead + Send>>;
+type Writer = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// A stream carried raw or through a codec in both directions.
+pub enum Compressed<T> {
+    Raw(T),
+    Coded {
+        codec: Codec,
+        reader: Reader,
+        writer: Writer,
+    },
+}
+
+impl<T> Compressed<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(inner: T, codec: Option<Codec>) -> Self {
+        let Some(codec) = codec else {
+            return Compressed::Raw(inner);
+        };
+        let (r, w) = tokio::io::split(inner);
+        let (reader, writer): (Reader, Writer) = match codec {
+            Codec::Zstd => (
+                Box::pin(ZstdDecoder::new(BufReader::<ReadHalf<T>>::new(r))),
+                Box::pin(ZstdEncoder::new(w)),
+            ),
+            Codec::Lz4 => (
+                Box::pin(Lz4Decoder::new(BufReader::<ReadHalf<T>>::new(r))),
+                Box::pin(Lz4Encoder::<WriteHalf<T>>::new(w)),
+            ),
+        };
+        Compressed::Coded {
+            codec,
+            reader,
+            writer,
+        }
+    }
+
+    pub fn codec(&self) -> Option<Codec> {
+        match self {
+            Compressed::Raw(_) => None,
+            Compressed::Coded { codec, .. } => Some(*codec),
+        }
+    }
+
+    /// Write `header` offering `offer`, wait for the answer if anything was
+    /// offered, and carry the stream with the codec agreed on.
+    pub async fn open(
+        mut inner: T,
+        header: StreamHeader,
+        offer: &[Codec],
+    ) -> io::Result<Compressed<T>> {
+        header.with_compression(offer).write_to(&mut inner).await?;
+        if offer.is_empty() {
+            return Ok(Compressed::Raw(inner));
+        }
+        let version = inner.read_u8().await?;
+        Self::answered(inner, version, offer).await
+    }
+
+    /// Finish reading the answer to `offer`, whose first byte, `version`,
+    /// has already been read.
+    async fn answered(mut inner: T, version: u8, offer: &[Codec]) -> io::Result<Self> {
+        let answer = StreamHeader::read_from(&mut [version].as_slice().chain(&mut inner)).await?;
+        let codec = answer.compression.first().copied();
+        if codec.is_some_and(|codec| !offer.contains(&codec)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer chose a codec that was not offered",
+            ));
+        }
+        Ok(Self::new(inner, codec))
+    }
+
+    /// Like [`open`](Self::open), but if the peer closes or resets the
+    /// stream before answering the offer at all, as peers from before
+    /// compression do, open another one with `reopen` and carry it raw.
+    /// Any other error is returned as is.
+    pub async fn open_or_raw<F, Fut>(
+        mut inner: T,
+        reopen: F,
+        header: StreamHeader,
+        offer: &[Codec],
+    ) -> io::Result<Compressed<T>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        if offer.is_empty() {
+            return Self::open(inner, header, offer).await;
+        }
+        header
+            .clone()
+            .with_compression(offer)
+            .write_to(&mut inner)
+            .await?;
+        match inner.read_u8().await {
+            Ok(version) => Self::answered(inner, version, offer).await,
+            Err(err) if dropped(&err) => {
+                debug!("compression offer not answered ({}), retry raw", err);
+                Self::open(reopen().await?, header, &[]).await
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Read the header the opening side wrote and, if it offers
+    /// compression, answer with the first codec of the offer in `accepted`.
+    pub async fn accept(mut inner: T, accepted: &[Codec]) -> io::Result<(StreamHeader, Self)> {
+        let header = StreamHeader::read_from(&mut inner).await?;
+        if header.compression.is_empty() {
+            return Ok((header, Compressed::Raw(inner)));
+        }
+        let codec = header
+            .compression
+            .iter()
+            .find(|codec| accepted.contains(codec))
+            .copied();
+        let answer = StreamHeader::new().with_compression(codec.as_slice());
+        answer.write_to(&mut inner).await?;
+        Ok((header, Self::new(inner, codec)))
+    }
+}
+
+/// Whether `err` means the peer went away without writing anything.
+fn dropped(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+    )
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Compressed<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Compressed::Raw(inner) => Pin::new(inner).poll_read(cx, buf),
+            Compressed::Coded { reader, .. } => reader.as_mut().poll_read(cx, buf),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Compressed<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Compressed::Raw(inner) => Pin::new(inner).poll_write(cx, buf),
+            Compressed::Coded { writer, .. } => writer.as_mut().poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Compressed::Raw(inner) => Pin::new(inner).poll_flush(cx),
+            Compressed::Coded { writer, .. } => writer.as_mut().poll_flush(cx),
+        }
+    }
+
+    /// Ends the compressed frame before shutting the stream down.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Compressed::Raw(inner) => Pin::new(inner).poll_shutdown(cx),
+            Compressed::Coded { writer, .. } => writer.as_mut().poll_shutdown(cx),
+        }
+    }
+}
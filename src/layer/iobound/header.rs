@@ -0,0 +1,224 @@
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::compress::Codec;
+use super::target::Target;
+
+const VERSION: u8 = 1;
+/// Version of headers offering or answering compression. Peers from before
+/// compression reject it and drop the stream instead of ignoring the offer
+/// and never answering, so the opening side can tell and retry raw.
+const VERSION_COMPRESSION: u8 = 2;
+
+const TYPE_TARGET: u8 = 1;
+const TYPE_SERVICE: u8 = 2;
+const TYPE_COMPRESSION: u8 = 3;
+const TYPE_CLIENT: u8 = 4;
+const TYPE_TOKEN: u8 = 5;
+
+/// Header written by the opening side at the start of every bidirectional
+/// tunnel stream, telling the accepting side where to forward it.
+///
+/// Layout: `version | length (u16) | fields`, each field being
+/// `type | length (u16) | value`. Unknown field types are skipped so newer
+/// peers can add fields without breaking older ones.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StreamHeader {
+    /// Address or `host:port` to connect to.
+    pub target: Option<Target>,
+    /// Name of a service configured on the accepting side.
+    pub service: Option<String>,
+    /// Codecs offered by the opening side, or the one the accepting side
+    /// chose in its answer, see [`Compressed`](super::compress::Compressed).
+    pub compression: Vec<Codec>,
+    /// Address of the client whose connection the stream carries.
+    pub client: Option<SocketAddr>,
+    /// Secret the accepting side may require before it forwards anything.
+    pub token: Option<String>,
+}
+
+impl StreamHeader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_target(mut self, target: Target) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    pub fn with_service(mut self, service: &str) -> Self {
+        self.service = Some(service.to_string());
+        self
+    }
+
+    pub fn with_compression(mut self, codecs: &[Codec]) -> Self {
+        self.compression = codecs.to_vec();
+        self
+    }
+
+    pub fn with_client(mut self, client: SocketAddr) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut fields = Vec::new();
+        if let Some(target) = &self.target {
+            put_field(&mut fields, TYPE_TARGET, target.to_string().as_bytes())?;
+        }
+        if let Some(service) = &self.service {
+            put_field(&mut fields, TYPE_SERVICE, service.as_bytes())?;
+        }
+        if !self.compression.is_empty() {
+            let names: Vec<&str> = self.compression.iter().map(|c| c.name()).collect();
+            put_field(&mut fields, TYPE_COMPRESSION, names.join(",").as_bytes())?;
+        }
+        if let Some(client) = &self.client {
+            put_field(&mut fields, TYPE_CLIENT, client.to_string().as_bytes())?;
+        }
+        if let Some(token) = &self.token {
+            put_field(&mut fields, TYPE_TOKEN, token.as_bytes())?;
+        }
+        let len = u16::try_from(fields.len()).map_err(|_| invalid("stream header too long"))?;
+
+        let mut buf = Vec::with_capacity(3 + fields.len());
+        buf.push(match self.compression.is_empty() {
+            true => VERSION,
+            false => VERSION_COMPRESSION,
+        });
+        buf.extend(len.to_be_bytes());
+        buf.extend(fields);
+        Ok(buf)
+    }
+
+    pub fn decode(fields: &[u8]) -> io::Result<Self> {
+        let mut header = StreamHeader::new();
+        let mut cur = 0;
+        while cur < fields.len() {
+            if fields.len() < cur + 3 {
+                return Err(invalid("truncated stream header"));
+            }
+            let kind = fields[cur];
+            let len = u16::from_be_bytes([fields[cur + 1], fields[cur + 2]]) as usize;
+            let start = cur + 3;
+            let end = start + len;
+            if fields.len() < end {
+                return Err(invalid("truncated stream header"));
+            }
+            let value = &fields[start..end];
+            match kind {
+                TYPE_TARGET => header.target = Some(utf8(value)?.parse()?),
+                TYPE_SERVICE => header.service = Some(utf8(value)?.to_string()),
+                // codecs this side doesn't know are left out of the offer
+                TYPE_COMPRESSION => {
+                    header.compression = utf8(value)?
+                        .split(',')
+                        .filter_map(|name| name.parse().ok())
+                        .collect()
+                }
+                TYPE_CLIENT => {
+                    let client = utf8(value)?.parse();
+                    header.client = Some(client.map_err(|_| invalid("bad client address"))?);
+                }
+                TYPE_TOKEN => header.token = Some(utf8(value)?.to_string()),
+                _ => {}
+            }
+            cur = end;
+        }
+        Ok(header)
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, w: &mut W) -> io::Result<()> {
+        let buf = self.encode()?;
+        w.write_all(&buf).await?;
+        w.flush().await
+    }
+
+    pub async fn read_from<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Self> {
+        let version = r.read_u8().await?;
+        if version != VERSION && version != VERSION_COMPRESSION {
+            return Err(invalid("unsupported stream header version"));
+        }
+        let len = r.read_u16().await? as usize;
+        let mut fields = vec![0u8; len];
+        r.read_exact(&mut fields).await?;
+        Self::decode(&fields)
+    }
+}
+
+fn put_field(buf: &mut Vec<u8>, kind: u8, value: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(value.len()).map_err(|_| invalid("stream header field too long"))?;
+    buf.push(kind);
+    buf.extend(len.to_be_bytes());
+    buf.extend(value);
+    Ok(())
+}
+
+fn utf8(value: &[u8]) -> io::Result<&str> {
+    std::str::from_utf8(value).map_err(|_| invalid("stream header field is not utf-8"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let header = StreamHeader::new()
+            .with_target("example.com:443".parse().unwrap())
+            .with_service("ssh")
+            .with_compression(&[Codec::Lz4, Codec::Zstd])
+            .with_client("[2001:db8::1]:5000".parse().unwrap())
+            .with_token("secret");
+        let buf = header.encode().unwrap();
+        assert_eq!(buf[0], VERSION_COMPRESSION);
+        let read = StreamHeader::read_from(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(read, header);
+    }
+
+    #[tokio::test]
+    async fn plain_header_keeps_version_one() {
+        let header = StreamHeader::new().with_service("web");
+        let buf = header.encode().unwrap();
+        assert_eq!(buf, b"\x01\x00\x06\x02\x00\x03web");
+        let read = StreamHeader::read_from(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(read, header);
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let mut fields = Vec::new();
+        put_field(&mut fields, 200, b"from a newer peer").unwrap();
+        put_field(&mut fields, TYPE_SERVICE, b"ssh").unwrap();
+        put_field(&mut fields, 201, b"").unwrap();
+        put_field(&mut fields, TYPE_COMPRESSION, b"brotli,zstd").unwrap();
+        let header = StreamHeader::decode(&fields).unwrap();
+        assert_eq!(header.service.as_deref(), Some("ssh"));
+        assert_eq!(header.compression, vec![Codec::Zstd]);
+        assert_eq!(header.target, None);
+    }
+
+    #[test]
+    fn truncated_fields_are_rejected() {
+        assert!(StreamHeader::decode(&[TYPE_SERVICE, 0]).is_err());
+        assert!(StreamHeader::decode(&[TYPE_SERVICE, 0, 4, b's']).is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_version_is_rejected() {
+        let buf = [9u8, 0, 0];
+        assert!(StreamHeader::read_from(&mut buf.as_slice()).await.is_err());
+    }
+}
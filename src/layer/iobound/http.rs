@@ -1,58 +1,129 @@
-use tokio::net::TcpSocket;
 use tower::Service;
 
 use std::io;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
+use super::io::BiStream;
+use super::shape::Shaper;
 use super::spawner::Spawner;
+use super::target::Target;
+use super::tunnel::{self, Timeouts};
+use crate::layer::{BoxFuture, InBound};
+use crate::metrics;
+use crate::net;
 use axum::{
     body::Body,
     extract::Request,
-    http::{Method, StatusCode},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
     response::{IntoResponse, Response},
 };
 
+use hyper::client::conn::http1::SendRequest;
 use hyper::upgrade::Upgraded;
 use hyper::{body::Incoming, server::conn::http1};
 
 use hyper_util::rt::TokioIo;
+use tracing::{debug, info_span, warn, Instrument};
+
+/// Headers that only apply to a single hop and must not be forwarded.
+const HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Upstream connection kept alive between the requests of one client
+/// connection, keyed by the target it was opened to.
+pub type Upstream = Arc<Mutex<Option<(Target, SendRequest<Body>)>>>;
 
 pub struct TcpProxy<T, S>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
-    S: Spawner<T> + Copy + Send + 'static,
+    S: Spawner<T> + 'static,
 {
     laddr: SocketAddr,
-    out: S,
+    out: Arc<S>,
+    timeouts: Timeouts,
+    shaper: Arc<Shaper>,
     _t: Option<T>,
 }
 
 impl<T, S> TcpProxy<T, S>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
-    S: Spawner<T> + Copy + Send + 'static,
+    S: Spawner<T> + 'static,
 {
     pub fn new(out: S, laddr: SocketAddr) -> std::io::Result<TcpProxy<T, S>> {
         let p = TcpProxy {
             laddr: laddr,
-            out: out,
+            out: Arc::new(out),
+            timeouts: Timeouts::default(),
+            shaper: Arc::new(Shaper::default()),
             _t: None,
         };
         Ok(p)
     }
 
+    /// Idle and lifetime limits of the client connections, and the connect
+    /// limit of the upstream ones.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Bandwidth limits of the client connections, shared with every proxy
+    /// given the same shaper.
+    pub fn with_shaper(mut self, shaper: Arc<Shaper>) -> Self {
+        self.shaper = shaper;
+        self
+    }
+
     pub async fn run(self) -> io::Result<()> {
-        let socket = TcpSocket::new_v4()?;
-        socket.bind(self.laddr)?;
-        let lis = socket.listen(1024)?;
+        let lis = net::tcp_listen(self.laddr)?;
 
         loop {
             let (stream, raddr) = lis.accept().await?;
-            println!("accept new conn: {}", raddr);
-            let io = TokioIo::new(stream);
+            let span = info_span!("http", peer = %raddr);
+            debug!(parent: &span, "accept new conn");
+            // hyper serves one end of a pipe while the client connection is
+            // tunneled to the other, under the same limits as a tcp inbound
+            let (client, served) = tokio::io::duplex(64 * 1024);
+            let label = self.laddr.to_string();
+            let timeouts = self.timeouts;
+            let limit = self.shaper.limit(&label, Some(raddr.ip()));
+            tokio::task::spawn(
+                async move {
+                    let mut conn = tunnel::Tunnel::labeled(
+                        BiStream::new(stream),
+                        BiStream::new(client),
+                        &label,
+                        Some(raddr),
+                    )
+                    .with_timeouts(timeouts)
+                    .with_limit(limit);
+                    match conn.copy().await {
+                        Ok((a, b)) => {
+                            metrics::global().add_bytes(&label, a, b);
+                            debug!(sent = a, received = b, "conn closed")
+                        }
+                        Err(err) => debug!("conn error: {}", err),
+                    }
+                }
+                .instrument(span.clone()),
+            );
+
+            let io = TokioIo::new(served);
+            let upstream = Upstream::default();
+            let out = self.out.clone();
 
             let tower_service = tower::service_fn(move |req: Request<_>| {
-                let proxy = ProxyService::new(self.out.clone());
+                let proxy = ProxyService::new(out.clone(), upstream.clone(), timeouts);
                 let req = req.map(Body::new);
                 async move {
                     if req.method() == Method::CONNECT {
@@ -67,17 +138,20 @@ where
                 tower_service.clone().call(request)
             });
 
-            tokio::task::spawn(async move {
-                if let Err(err) = http1::Builder::new()
-                    .preserve_header_case(true)
-                    .title_case_headers(true)
-                    .serve_connection(io, hyper_service)
-                    .with_upgrades()
-                    .await
-                {
-                    println!("Failed to serve connection: {:?}", err);
+            tokio::task::spawn(
+                async move {
+                    if let Err(err) = http1::Builder::new()
+                        .preserve_header_case(true)
+                        .title_case_headers(true)
+                        .serve_connection(io, hyper_service)
+                        .with_upgrades()
+                        .await
+                    {
+                        debug!("failed to serve connection: {:?}", err);
+                    }
                 }
-            });
+                .instrument(span),
+            );
         }
     }
 }
@@ -85,89 +159,225 @@ where
 pub struct ProxyService<T, S>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
-    S: Spawner<T> + Copy + Send + 'static,
+    S: Spawner<T> + 'static,
 {
-    out: S,
+    out: Arc<S>,
+    upstream: Upstream,
+    timeouts: Timeouts,
     _t: Option<T>,
 }
 
 impl<T, S> ProxyService<T, S>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
-    S: Spawner<T> + Copy + Send + 'static,
+    S: Spawner<T> + 'static,
 {
-    pub fn new(out: S) -> ProxyService<T, S> {
-        ProxyService { out: out, _t: None }
+    pub fn new(out: Arc<S>, upstream: Upstream, timeouts: Timeouts) -> ProxyService<T, S> {
+        ProxyService {
+            out,
+            upstream,
+            timeouts,
+            _t: None,
+        }
     }
 
     async fn proxy(self, req: Request) -> Result<Response, hyper::Error> {
-        if let Some(host_addr) = req.uri().authority().map(|auth| auth.to_string()) {
-            tokio::task::spawn(async move {
+        let target = match req
+            .uri()
+            .authority()
+            .map(|auth| auth.as_str().parse::<Target>())
+        {
+            Some(Ok(target)) => target,
+            _ => {
+                debug!("CONNECT host is not socket addr: {:?}", req.uri());
+                return Ok((StatusCode::BAD_REQUEST, "invalid CONNECT authority").into_response());
+            }
+        };
+
+        let dial = self.out.spawn_target(target.clone());
+        let server = match self.timeouts.connect(dial).await {
+            Ok(server) => server,
+            Err(e) => {
+                warn!("connect to {} error: {}", target, e);
+                return Ok(bad_gateway(&target, e));
+            }
+        };
+
+        tokio::task::spawn(
+            async move {
                 match hyper::upgrade::on(req).await {
                     Ok(upgraded) => {
-                        if let Err(e) = self.tunnel(upgraded, host_addr).await {
-                            println!("server io error: {}", e);
+                        if let Err(e) = Self::tunnel(upgraded, server).await {
+                            debug!("server io error: {}", e);
                         };
                     }
-                    Err(e) => println!("upgrade error: {}", e),
+                    Err(e) => debug!("upgrade error: {}", e),
                 }
-            });
+            }
+            .instrument(info_span!("connect", %target)),
+        );
 
-            Ok(Response::new(Body::empty()))
-        } else {
-            println!("CONNECT host is not socket addr: {:?}", req.uri());
-            Ok((
-                StatusCode::BAD_REQUEST,
-                "CONNECT must be to a socket address",
-            )
-                .into_response())
-        }
+        Ok(Response::new(Body::empty()))
     }
 
-    async fn tunnel(self, upgraded: Upgraded, addr: String) -> std::io::Result<()> {
+    async fn tunnel(upgraded: Upgraded, mut server: BiStream<T>) -> std::io::Result<()> {
         let mut upgraded = TokioIo::new(upgraded);
-        let raddr = addr.parse().unwrap();
-        let mut server = self.out.spawn_target(raddr).await?;
         let (from_client, from_server) =
-            tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
+            tokio::io::copy_bidirectional(&mut upgraded, server.inner_mut()).await?;
 
-        println!(
-            "client wrote {} bytes and received {} bytes",
-            from_client, from_server
-        );
+        debug!(sent = from_client, received = from_server, "tunnel closed");
 
         Ok(())
     }
-    async fn request(self, req: Request) -> Result<Response, hyper::Error> {
-        let client = hyper::client::conn::http1::Builder::new();
-        if let Some(host_addr) = req.uri().authority().map(|auth| auth.to_string()) {
-            match host_addr.parse() {
-                Ok(raddr) => {
-                    println!("connect to {}", raddr);
-                    let stream = TcpSocket::new_v4().unwrap().connect(raddr).await.unwrap();
-                    let (mut send, _) = client.handshake(TokioIo::new(stream)).await?;
-
-                    let resp = send.send_request(req).await?;
-                    return Ok(resp.into_response());
-                }
-                Err(e) => {
-                    panic!("{} err: {}", host_addr, e)
-                }
+    /// Forward a plain http request through `out`, reusing the upstream
+    /// connection of the previous request when it went to the same target.
+    async fn request(self, mut req: Request) -> Result<Response, hyper::Error> {
+        let target = match request_target(&req) {
+            Ok(target) => target,
+            Err(e) => return Ok((StatusCode::BAD_REQUEST, e.to_string()).into_response()),
+        };
+
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/")
+            .to_string();
+        *req.uri_mut() = path
+            .parse::<Uri>()
+            .unwrap_or_else(|_| Uri::from_static("/"));
+        strip_hop_headers(req.headers_mut());
+        if !req.headers().contains_key(header::HOST) {
+            if let Ok(host) = HeaderValue::from_str(&target.to_string()) {
+                req.headers_mut().insert(header::HOST, host);
+            }
+        }
+
+        let dial = Self::upstream(&self.out, &self.upstream, &target, self.timeouts);
+        let mut send = match dial.await {
+            Ok(send) => send,
+            Err(e) => {
+                warn!("connect to {} error: {}", target, e);
+                return Ok(bad_gateway(&target, e));
+            }
+        };
+
+        let mut resp = match send.send_request(req).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                warn!("request to {} error: {}", target, e);
+                return Ok(bad_gateway(&target, io::Error::other(e)));
+            }
+        };
+
+        let close = resp
+            .headers()
+            .get(header::CONNECTION)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.eq_ignore_ascii_case("close"))
+            .unwrap_or(false);
+        if !close {
+            *self.upstream.lock().unwrap() = Some((target, send));
+        }
+        strip_hop_headers(resp.headers_mut());
+        Ok(resp.into_response())
+    }
+
+    /// Take the kept-alive upstream sender for `target`, or dial a new one
+    /// through `out` within the connect timeout.
+    async fn upstream(
+        out: &S,
+        upstream: &Upstream,
+        target: &Target,
+        timeouts: Timeouts,
+    ) -> io::Result<SendRequest<Body>> {
+        let cached = upstream.lock().unwrap().take();
+        if let Some((cached_target, mut send)) = cached {
+            if &cached_target == target && !send.is_closed() && send.ready().await.is_ok() {
+                return Ok(send);
             }
-        } else {
-            panic!("addr parse error");
         }
+
+        let stream = timeouts.connect(out.spawn_target(target.clone())).await?;
+        let (send, conn) = hyper::client::conn::http1::Builder::new()
+            .handshake(TokioIo::new(stream.into_inner()))
+            .await
+            .map_err(io::Error::other)?;
+        tokio::task::spawn(
+            async move {
+                if let Err(err) = conn.await {
+                    debug!("upstream connection error: {}", err);
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(send)
     }
 }
 
+impl<T, S> InBound for TcpProxy<T, S>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
+    S: Spawner<T> + 'static,
+{
+    fn recv(self: Box<Self>) -> BoxFuture<'static, io::Result<()>> {
+        Box::pin(self.run())
+    }
+}
+
+/// Where a non-CONNECT request goes: the authority of an absolute-form uri,
+/// falling back to the Host header.
+fn request_target(req: &Request) -> io::Result<Target> {
+    if let Some(auth) = req.uri().authority() {
+        return Target::new(auth.host(), auth.port_u16().unwrap_or(80));
+    }
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "request has no host"))?;
+    match host.parse::<Target>() {
+        Ok(target) => Ok(target),
+        Err(_) => Target::new(host, 80),
+    }
+}
+
+/// Remove hop-by-hop headers, including any named in `Connection`.
+fn strip_hop_headers(headers: &mut HeaderMap) {
+    let named: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty())
+        .collect();
+    for name in named.iter() {
+        headers.remove(name.as_str());
+    }
+    for name in HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+fn bad_gateway(target: &Target, err: std::io::Error) -> Response {
+    (
+        StatusCode::BAD_GATEWAY,
+        format!("connect to {} failed: {}", target, err),
+    )
+        .into_response()
+}
+
 impl<T, S> Clone for ProxyService<T, S>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
-    S: Spawner<T> + Copy + Send + 'static,
+    S: Spawner<T> + 'static,
 {
     fn clone(&self) -> Self {
         Self {
             out: self.out.clone(),
+            upstream: self.upstream.clone(),
+            timeouts: self.timeouts,
             _t: None,
         }
     }
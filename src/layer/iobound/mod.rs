@@ -1,8 +1,13 @@
+pub mod compress;
+pub mod header;
 pub mod http;
 pub mod io;
 pub mod quicin;
 pub mod quicout;
+pub mod shape;
+pub mod socks5;
 pub mod spawner;
+pub mod target;
 pub mod tcpin;
 pub mod tcpout;
 pub mod tunnel;
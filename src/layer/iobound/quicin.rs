@@ -1,53 +1,347 @@
-use s2n_quic::Connection;
+use ring::constant_time;
+use s2n_quic::provider::io::tokio::Builder as IOBuilder;
+use s2n_quic::stream::BidirectionalStream;
+use s2n_quic::{Connection, Server};
 
+use std::collections::HashMap;
 use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug, debug_span, field, info, info_span, warn, Instrument};
 
+use super::compress::{Codec, Compressed};
 use super::io::BiStream;
+use super::shape::Shaper;
 use super::spawner::Spawner;
-use super::tunnel;
+use super::target::Target;
+use super::tunnel::{self, Timeouts};
+use crate::layer::{BoxFuture, InBound};
+use crate::metrics;
+use crate::net;
 
 pub struct QuicProxy<T, S>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
-    S: Spawner<T> + Copy + Send,
+    S: Spawner<T> + 'static,
 {
     conn: Connection,
-    out: S,
+    out: Arc<S>,
+    services: HashMap<String, Target>,
+    token: Option<String>,
+    allow_targets: bool,
+    timeouts: Timeouts,
+    shaper: Arc<Shaper>,
     _t: Option<T>,
 }
 
 impl<T, S> QuicProxy<T, S>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
-    S: Spawner<T> + Copy + Send,
+    S: Spawner<T> + 'static,
 {
     pub fn new(out: S, conn: Connection) -> std::io::Result<QuicProxy<T, S>> {
         let p = QuicProxy {
             conn: conn,
-            out: out,
+            out: Arc::new(out),
+            services: HashMap::new(),
+            token: None,
+            allow_targets: false,
+            timeouts: Timeouts::default(),
+            shaper: Arc::new(Shaper::default()),
             _t: None,
         };
         Ok(p)
     }
+
+    fn shared(server: &QuicServer<T, S>, conn: Connection) -> Self {
+        QuicProxy {
+            conn,
+            out: server.out.clone(),
+            services: server.services.clone(),
+            token: server.token.clone(),
+            allow_targets: server.allow_targets,
+            timeouts: server.timeouts,
+            shaper: server.shaper.clone(),
+            _t: None,
+        }
+    }
+
+    /// Forward streams whose header names `name` to `target`.
+    pub fn with_service(mut self, name: &str, target: Target) -> Self {
+        self.services.insert(name.to_string(), target);
+        self
+    }
+
+    /// Only serve streams whose header carries `token`.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Forward streams to any target their header names, not only to the
+    /// targets of the configured services.
+    pub fn with_allow_targets(mut self) -> Self {
+        self.allow_targets = true;
+        self
+    }
+
+    /// Idle and lifetime limits of the forwarded streams.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Bandwidth limits of the forwarded streams, shared with every proxy
+    /// given the same shaper.
+    pub fn with_shaper(mut self, shaper: Arc<Shaper>) -> Self {
+        self.shaper = shaper;
+        self
+    }
+
     pub async fn run(self) -> io::Result<()> {
         let mut conn = self.conn;
+        let _active = metrics::connection("quic_in");
+        let peer = conn.remote_addr().ok();
         loop {
-            if let Some(stream_in) = conn.accept().await? {
-                println!("accept new conn: {}", stream_in.id());
-                let stream_out = self.out.spawn().await?;
-                let stream_in = BiStream::new(stream_in);
-                let mut conn = tunnel::Tunnel::new(stream_in, stream_out);
-                tokio::task::spawn(async move {
-                    match conn.copy().await {
-                        Ok((a, b)) => {
-                            println!("copy {}:{}", a, b)
-                        }
-                        Err(err) => {
-                            println!("{}", err)
-                        }
+            let Some(stream_in) = conn.accept_bidirectional_stream().await? else {
+                // the peer closed the connection
+                return Ok(());
+            };
+            let span = debug_span!("stream", id = stream_in.id());
+            debug!(parent: &span, "accept new stream");
+            let out = self.out.clone();
+            let rules = Rules {
+                services: self.services.clone(),
+                token: self.token.clone(),
+                allow_targets: self.allow_targets,
+            };
+            let timeouts = self.timeouts;
+            let shaper = self.shaper.clone();
+            tokio::task::spawn(
+                async move {
+                    let _active = metrics::stream("quic_in");
+                    match Self::handle(out, &rules, timeouts, &shaper, peer, stream_in).await {
+                        Ok((a, b)) => debug!(sent = a, received = b, "stream closed"),
+                        Err(err) => debug!("stream error: {}", err),
                     }
-                });
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    /// Read the stream header and forward the stream to the target it asks
+    /// for, or to the default outbound if it names none, decompressing it if
+    /// the header offers a codec.
+    async fn handle(
+        out: Arc<S>,
+        rules: &Rules,
+        timeouts: Timeouts,
+        shaper: &Shaper,
+        peer: Option<SocketAddr>,
+        stream_in: BidirectionalStream,
+    ) -> io::Result<(u64, u64)> {
+        // any codec the opening side offers is fine here
+        let (header, stream_in) = Compressed::accept(stream_in, Codec::ALL).await?;
+        rules.check(header.token.as_deref(), header.target.as_ref())?;
+        let label = match (&header.target, &header.service) {
+            (None, Some(service)) => service.clone(),
+            _ => "default".to_string(),
+        };
+        let stream_out = match (header.target, header.service) {
+            (Some(target), _) => out.spawn_target(target).await?,
+            (None, Some(service)) => match rules.services.get(&service) {
+                Some(target) => out.spawn_target(target.clone()).await?,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("unknown service {}", service),
+                    ))
+                }
+            },
+            (None, None) => out.spawn().await?,
+        };
+        let stream_in = BiStream::new(stream_in);
+        let limit = shaper.limit(&label, peer.map(|p| p.ip()));
+        let mut conn = tunnel::Tunnel::labeled(stream_in, stream_out, &label, peer)
+            .with_timeouts(timeouts)
+            .with_limit(limit);
+        let (a, b) = conn.copy().await?;
+        metrics::global().add_bytes(&label, a, b);
+        Ok((a, b))
+    }
+}
+
+/// What the streams of a connection may ask for.
+struct Rules {
+    services: HashMap<String, Target>,
+    token: Option<String>,
+    allow_targets: bool,
+}
+
+impl Rules {
+    /// Refuse streams without the token, if one is required, and streams
+    /// naming a target no service points at unless any target is allowed.
+    fn check(&self, token: Option<&str>, target: Option<&Target>) -> io::Result<()> {
+        if let Some(required) = &self.token {
+            let given = token.unwrap_or_default();
+            if constant_time::verify_slices_are_equal(given.as_bytes(), required.as_bytes())
+                .is_err()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "missing or wrong token",
+                ));
+            }
+        }
+        match target {
+            Some(target) if !self.allow_targets && !self.services.values().any(|t| t == target) => {
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("target {} is not allowed", target),
+                ))
             }
+            _ => Ok(()),
         }
     }
 }
+
+impl<T, S> InBound for QuicProxy<T, S>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
+    S: Spawner<T> + 'static,
+{
+    fn recv(self: Box<Self>) -> BoxFuture<'static, io::Result<()>> {
+        Box::pin(self.run())
+    }
+}
+
+/// Listens for QUIC connections on a public udp port and serves the streams
+/// of each with a [`QuicProxy`], for tunnels where one side is reachable
+/// directly and no hole punching is needed.
+pub struct QuicServer<T, S>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
+    S: Spawner<T> + 'static,
+{
+    laddr: SocketAddr,
+    cert: String,
+    key: String,
+    out: Arc<S>,
+    services: HashMap<String, Target>,
+    token: Option<String>,
+    allow_targets: bool,
+    timeouts: Timeouts,
+    shaper: Arc<Shaper>,
+    _t: Option<T>,
+}
+
+impl<T, S> QuicServer<T, S>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
+    S: Spawner<T> + 'static,
+{
+    pub fn new(out: S, laddr: SocketAddr, cert: &str, key: &str) -> io::Result<QuicServer<T, S>> {
+        let p = QuicServer {
+            laddr,
+            cert: cert.to_string(),
+            key: key.to_string(),
+            out: Arc::new(out),
+            services: HashMap::new(),
+            token: None,
+            allow_targets: false,
+            timeouts: Timeouts::default(),
+            shaper: Arc::new(Shaper::default()),
+            _t: None,
+        };
+        Ok(p)
+    }
+
+    /// Forward streams whose header names `name` to `target`.
+    pub fn with_service(mut self, name: &str, target: Target) -> Self {
+        self.services.insert(name.to_string(), target);
+        self
+    }
+
+    /// Only serve streams whose header carries `token`.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Forward streams to any target their header names, not only to the
+    /// targets of the configured services.
+    pub fn with_allow_targets(mut self) -> Self {
+        self.allow_targets = true;
+        self
+    }
+
+    /// Idle and lifetime limits of the forwarded streams.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Bandwidth limits of the forwarded streams, shared with every proxy
+    /// given the same shaper.
+    pub fn with_shaper(mut self, shaper: Arc<Shaper>) -> Self {
+        self.shaper = shaper;
+        self
+    }
+
+    pub async fn run(self) -> io::Result<()> {
+        if self.token.is_none() && !self.laddr.ip().is_loopback() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("quic server on {} needs a token", self.laddr),
+            ));
+        }
+        let tx = net::udp_bind(self.laddr)?.into_std()?;
+        let rx = tx.try_clone()?;
+        let tls = s2n_quic::provider::tls::default::Server::builder()
+            .with_certificate(Path::new(&self.cert), Path::new(&self.key))
+            .and_then(|b| b.build())
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        let socket_io = IOBuilder::default()
+            .with_tx_socket(tx)?
+            .with_rx_socket(rx)?
+            .build()?;
+        let mut server = Server::builder()
+            .with_tls(tls)
+            .map_err(|e| io::Error::other(e.to_string()))?
+            .with_io(socket_io)
+            .map_err(|e| io::Error::other(e.to_string()))?
+            .start()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        info!("quic server listen on {}", self.laddr);
+
+        while let Some(conn) = server.accept().await {
+            let span = match conn.remote_addr() {
+                Ok(peer) => info_span!("quic", peer = %peer),
+                Err(_) => info_span!("quic", peer = field::Empty),
+            };
+            info!(parent: &span, "connection accepted");
+            let proxy = QuicProxy::shared(&self, conn);
+            tokio::task::spawn(
+                async move {
+                    if let Err(err) = proxy.run().await {
+                        warn!("quic connection error: {}", err);
+                    }
+                }
+                .instrument(span),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<T, S> InBound for QuicServer<T, S>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
+    S: Spawner<T> + 'static,
+{
+    fn recv(self: Box<Self>) -> BoxFuture<'static, io::Result<()>> {
+        Box::pin(self.run())
+    }
+}
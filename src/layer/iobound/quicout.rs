@@ -1,30 +1,274 @@
-use std::net::SocketAddr;
-
+use super::header::StreamHeader;
 use super::io::BiStream;
-use s2n_quic::{stream::BidirectionalStream, Connection};
+use super::target::Target;
+use crate::frontend::{client_tls, insecure_client_tls};
+use crate::layer::{BoxFuture, BoxIo, OutBound};
+use crate::metrics;
+use crate::net;
+use crate::pool::{Dial, Probe};
+use s2n_quic::client::Connect;
+use s2n_quic::connection::Handle;
+use s2n_quic::provider::io::tokio::Builder as IOBuilder;
+use s2n_quic::{stream::BidirectionalStream, Client, Connection};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
 
+use super::compress::{Codec, Compressed};
 use super::spawner::Spawner;
 
+/// Opens a new stream on a QUIC connection for every outbound request.
+/// Clones share the connection, which stays open while any clone is alive.
+#[derive(Clone)]
 pub struct QuicOutStream {
-    conn: Connection,
+    handle: Handle,
+    compression: Vec<Codec>,
 }
 
 impl QuicOutStream {
     pub fn new(conn: Connection) -> Self {
-        Self { conn: conn }
+        Self {
+            handle: conn.handle(),
+            compression: Vec::new(),
+        }
+    }
+
+    /// Offer `codecs` for every stream, see [`Compressed`].
+    pub fn with_compression(mut self, codecs: &[Codec]) -> Self {
+        self.compression = codecs.to_vec();
+        self
+    }
+}
+
+/// Open a stream and write its header, announcing `target` if any and
+/// offering `compression`.
+async fn open(
+    mut handle: Handle,
+    target: Option<Target>,
+    token: Option<&str>,
+    compression: &[Codec],
+) -> std::io::Result<BiStream<Stream>> {
+    let stream = handle.open_bidirectional_stream().await?;
+    let reopen = || async move { Ok(handle.open_bidirectional_stream().await?) };
+    announce(stream, reopen, target, token, compression).await
+}
+
+/// Write the header of a freshly opened stream. Until then the server does
+/// not know about the stream, so it does not dial anything for it either.
+/// `reopen` opens the raw retry if the server does not answer `compression`.
+async fn announce<F, Fut>(
+    stream: BidirectionalStream,
+    reopen: F,
+    target: Option<Target>,
+    token: Option<&str>,
+    compression: &[Codec],
+) -> std::io::Result<BiStream<Stream>>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = std::io::Result<BidirectionalStream>>,
+{
+    let mut header = StreamHeader::new();
+    if let Some(target) = target {
+        header = header.with_target(target);
+    }
+    if let Some(token) = token {
+        header = header.with_token(token);
+    }
+    let stream = Compressed::open_or_raw(stream, reopen, header, compression).await?;
+    Ok(BiStream::new(stream))
+}
+
+/// A QUIC stream, compressed if both sides agreed on a codec.
+pub type Stream = Compressed<BidirectionalStream>;
+
+impl Spawner<Stream> for QuicOutStream {
+    async fn spawn(&self) -> std::io::Result<BiStream<Stream>> {
+        return open(self.handle.clone(), None, None, &self.compression).await;
+    }
+    async fn spawn_target(&self, target: Target) -> std::io::Result<BiStream<Stream>> {
+        return open(self.handle.clone(), Some(target), None, &self.compression).await;
+    }
+}
+
+impl OutBound for QuicOutStream {
+    fn send(&self, target: Option<Target>) -> BoxFuture<'_, std::io::Result<BiStream<BoxIo>>> {
+        Box::pin(async move {
+            let stream = match target {
+                Some(target) => self.spawn_target(target).await?,
+                None => self.spawn().await?,
+            };
+            let stream: BoxIo = Box::new(stream.into_inner());
+            Ok(BiStream::new(stream))
+        })
+    }
+}
+
+/// Client side of a plain QUIC tunnel: dials a [`QuicServer`] with a public
+/// address, without NAT traversal, and opens a stream on that connection
+/// for every outbound request. The connection is made on first use and
+/// made again once it is lost. The server's certificate has to be issued
+/// for `server_name` unless [`with_insecure`](Self::with_insecure) is set.
+///
+/// [`QuicServer`]: super::quicin::QuicServer
+pub struct QuicDialer {
+    server: String,
+    server_name: String,
+    cert: String,
+    insecure: bool,
+    token: Option<String>,
+    compression: Vec<Codec>,
+    conn: Mutex<Option<(Client, Handle, metrics::Active)>>,
+}
+
+impl QuicDialer {
+    pub fn new(server: &str, server_name: &str, cert: &str) -> Self {
+        Self {
+            server: server.to_string(),
+            server_name: server_name.to_string(),
+            cert: cert.to_string(),
+            insecure: false,
+            token: None,
+            compression: Vec::new(),
+            conn: Mutex::new(None),
+        }
+    }
+
+    /// Accept any server holding a certificate issued by `cert`, whatever
+    /// name it was issued for. Only for test setups where every peer
+    /// shares one certificate.
+    pub fn with_insecure(mut self) -> Self {
+        self.insecure = true;
+        self
+    }
+
+    /// Offer `codecs` for every stream, see [`Compressed`].
+    pub fn with_compression(mut self, codecs: &[Codec]) -> Self {
+        self.compression = codecs.to_vec();
+        self
     }
+
+    /// Send `token` with every stream, for servers that require one.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    async fn connect(&self) -> std::io::Result<(Client, Handle)> {
+        let tx = net::udp_bind_any()?;
+        let raddr = net::resolve_from(&tx.local_addr()?, &self.server).await?;
+        let tx = tx.into_std()?;
+        let rx = tx.try_clone()?;
+        let socket_io = IOBuilder::default()
+            .with_tx_socket(tx)?
+            .with_rx_socket(rx)?
+            .build()?;
+        let tls = match self.insecure {
+            true => insecure_client_tls(&self.cert),
+            false => client_tls(&self.cert),
+        }
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+        let client = Client::builder()
+            .with_tls(tls)
+            .map_err(|e| std::io::Error::other(e.to_string()))?
+            .with_io(socket_io)
+            .map_err(|e| std::io::Error::other(e.to_string()))?
+            .start()
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        let connect = Connect::new(raddr).with_server_name(self.server_name.as_str());
+        let mut conn = client.connect(connect).await?;
+        conn.keep_alive(true)?;
+        info!("quic connected to {}", self.server);
+        Ok((client, conn.handle()))
+    }
+
+    /// Open a stream with nothing written to it yet, reconnecting once if
+    /// the current connection is gone.
+    async fn open_raw(&self) -> std::io::Result<BidirectionalStream> {
+        let current = self.conn.lock().await.as_ref().map(|(_, h, _)| h.clone());
+        if let Some(mut handle) = current {
+            match handle.open_bidirectional_stream().await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => warn!("quic connection to {} lost: {}", self.server, err),
+            }
+        }
+
+        let mut conn = self.conn.lock().await;
+        // another task may have reconnected in the meantime
+        if let Some((_, handle, _)) = conn.as_ref() {
+            if let Ok(stream) = handle.clone().open_bidirectional_stream().await {
+                return Ok(stream);
+            }
+        }
+        let (client, mut handle) = self.connect().await?;
+        *conn = Some((client, handle.clone(), metrics::connection("quic_out")));
+        drop(conn);
+        Ok(handle.open_bidirectional_stream().await?)
+    }
+
+    /// Open a stream and write its header, announcing `target` if any.
+    async fn open(&self, target: Option<Target>) -> std::io::Result<BiStream<Stream>> {
+        let stream = self.open_raw().await?;
+        let token = self.token.as_deref();
+        announce(stream, || self.open_raw(), target, token, &self.compression).await
+    }
+}
+
+impl Spawner<Stream> for QuicDialer {
+    async fn spawn(&self) -> std::io::Result<BiStream<Stream>> {
+        self.open(None).await
+    }
+    async fn spawn_target(&self, target: Target) -> std::io::Result<BiStream<Stream>> {
+        self.open(Some(target)).await
+    }
+}
+
+/// A connection of a [`QuicDialer`] dialed ahead of time, carrying one
+/// stream after another.
+#[derive(Clone)]
+pub struct QuicConn {
+    _client: Client,
+    handle: Handle,
+    _active: Arc<metrics::Active>,
 }
 
-impl Spawner<BidirectionalStream> for QuicOutStream {
-    async fn spawn(mut self) -> std::io::Result<BiStream<BidirectionalStream>> {
-        let stream = self.conn.open_bidirectional_stream().await?;
-        return Ok(BiStream::new(stream));
+impl Probe for QuicConn {
+    fn is_alive(&self) -> bool {
+        // fails once the connection is closing or closed, and keeps an
+        // idle one from timing out otherwise
+        self.handle.clone().ping().is_ok()
+    }
+}
+
+impl Dial for QuicDialer {
+    type Conn = QuicConn;
+    type Stream = Stream;
+
+    async fn dial(&self) -> std::io::Result<QuicConn> {
+        let (client, handle) = self.connect().await?;
+        Ok(QuicConn {
+            _client: client,
+            handle,
+            _active: Arc::new(metrics::connection("quic_out")),
+        })
     }
-    async fn spawn_target(
-        mut self,
-        _target: SocketAddr,
-    ) -> std::io::Result<BiStream<BidirectionalStream>> {
-        let stream = self.conn.open_bidirectional_stream().await?;
-        return Ok(BiStream::new(stream));
+
+    async fn finish(&self, conn: QuicConn) -> std::io::Result<BiStream<Stream>> {
+        open(conn.handle, None, self.token.as_deref(), &self.compression).await
+    }
+
+    fn reuse(&self, conn: &QuicConn) -> Option<QuicConn> {
+        Some(conn.clone())
+    }
+}
+
+impl OutBound for QuicDialer {
+    fn send(&self, target: Option<Target>) -> BoxFuture<'_, std::io::Result<BiStream<BoxIo>>> {
+        Box::pin(async move {
+            let stream = self.open(target).await?;
+            let stream: BoxIo = Box::new(stream.into_inner());
+            Ok(BiStream::new(stream))
+        })
     }
 }
@@ -0,0 +1,180 @@
+//! Bandwidth limits for tunnels: token buckets in bytes, shared by every
+//! tunnel of a service, of a frontend, or of the whole process, and drained
+//! by the reads in [`Tunnel::copy`](super::tunnel::Tunnel::copy).
+//!
+//! s2n-quic has no stream priorities, so priority is applied here instead:
+//! lower priority tunnels leave part of each shared bucket to higher ones
+//! and only get to it once the bucket refills past their reserve.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::limit::Rate;
+
+/// Share of a bucket a priority may not touch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    fn reserve(&self) -> f64 {
+        match self {
+            Priority::High => 0.0,
+            Priority::Normal => 0.25,
+            Priority::Low => 0.5,
+        }
+    }
+}
+
+struct State {
+    tokens: f64,
+    last: Instant,
+}
+
+/// A token bucket of bytes, starting full. Reads may overdraw it, the
+/// debt is paid back before anyone reads again. Rates below a byte per
+/// second are raised to one so the bucket always refills.
+pub struct Bucket {
+    rate: Rate,
+    state: Mutex<State>,
+}
+
+impl Bucket {
+    pub fn new(rate: Rate) -> Self {
+        let rate = Rate::new(rate.per_sec.max(1.0), rate.burst.max(1.0));
+        Bucket {
+            rate,
+            state: Mutex::new(State {
+                tokens: rate.burst,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Bytes a reader of `priority` may take now, or how long until it may.
+    fn allowance(&self, priority: Priority) -> Result<usize, Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate.per_sec).min(self.rate.burst);
+        state.last = now;
+        let usable = state.tokens - self.rate.burst * priority.reserve();
+        if usable >= 1.0 {
+            return Ok(usable as usize);
+        }
+        Err(Duration::from_secs_f64((1.0 - usable) / self.rate.per_sec))
+    }
+
+    fn consume(&self, n: usize) {
+        self.state.lock().unwrap().tokens -= n as f64;
+    }
+}
+
+/// The buckets one tunnel draws from. The default limits nothing.
+#[derive(Clone, Default)]
+pub struct Limit {
+    buckets: Vec<Arc<Bucket>>,
+    priority: Priority,
+}
+
+impl Limit {
+    /// Bytes that may be read now, or how long to wait first.
+    pub fn allowance(&self) -> Result<usize, Duration> {
+        let mut allowed = usize::MAX;
+        let mut wait = None;
+        for bucket in self.buckets.iter() {
+            match bucket.allowance(self.priority) {
+                Ok(n) => allowed = allowed.min(n),
+                Err(w) => wait = Some(wait.map_or(w, |wait: Duration| wait.max(w))),
+            }
+        }
+        match wait {
+            Some(wait) => Err(wait),
+            None => Ok(allowed),
+        }
+    }
+
+    /// Charge `n` bytes read to every bucket.
+    pub fn consume(&self, n: usize) {
+        for bucket in self.buckets.iter() {
+            bucket.consume(n);
+        }
+    }
+}
+
+/// Bandwidth limits of a process. Rates are bytes per second counted over
+/// both directions of a tunnel.
+#[derive(Default)]
+pub struct Shaper {
+    global: Option<Arc<Bucket>>,
+    services: HashMap<String, Rate>,
+    per_frontend: Option<Rate>,
+    priorities: HashMap<String, Priority>,
+    /// Buckets of services and frontends with tunnels open.
+    shared: Mutex<HashMap<String, Weak<Bucket>>>,
+}
+
+impl Shaper {
+    /// Limit all tunnels together to `rate`.
+    pub fn with_global(mut self, rate: Rate) -> Self {
+        self.global = Some(Arc::new(Bucket::new(rate)));
+        self
+    }
+
+    /// Limit the tunnels of `service` together to `rate`.
+    pub fn with_service(mut self, service: &str, rate: Rate) -> Self {
+        self.services.insert(service.to_string(), rate);
+        self
+    }
+
+    /// Limit the tunnels of each frontend address together to `rate`.
+    pub fn with_frontend(mut self, rate: Rate) -> Self {
+        self.per_frontend = Some(rate);
+        self
+    }
+
+    /// Serve the tunnels of `service` at `priority`, `normal` by default.
+    pub fn with_priority(mut self, service: &str, priority: Priority) -> Self {
+        self.priorities.insert(service.to_string(), priority);
+        self
+    }
+
+    fn shared(&self, key: String, rate: Rate) -> Arc<Bucket> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(bucket) = shared.get(&key).and_then(|b| b.upgrade()) {
+            return bucket;
+        }
+        shared.retain(|_, b| b.strong_count() > 0);
+        let bucket = Arc::new(Bucket::new(rate));
+        shared.insert(key, Arc::downgrade(&bucket));
+        bucket
+    }
+
+    /// The limit of a tunnel toward `service` for the frontend at
+    /// `frontend`, if known.
+    pub fn limit(&self, service: &str, frontend: Option<IpAddr>) -> Limit {
+        let mut buckets = Vec::new();
+        if let Some(rate) = self.services.get(service) {
+            buckets.push(self.shared(format!("service/{}", service), *rate));
+        }
+        if let (Some(rate), Some(ip)) = (self.per_frontend, frontend) {
+            buckets.push(self.shared(format!("frontend/{}", ip), rate));
+        }
+        if let Some(global) = self.global.as_ref() {
+            buckets.push(global.clone());
+        }
+        Limit {
+            buckets,
+            priority: self.priorities.get(service).copied().unwrap_or_default(),
+        }
+    }
+}
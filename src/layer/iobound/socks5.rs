@@ -0,0 +1,398 @@
+use ring::constant_time;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, info_span, Instrument};
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::io::BiStream;
+use super::shape::{Limit, Shaper};
+use super::spawner::Spawner;
+use super::target::Target;
+use super::tunnel::{self, Timeouts};
+use crate::layer::{BoxFuture, InBound};
+use crate::metrics;
+use crate::net;
+
+/// Give up on clients that have not sent a complete request after this long.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NONE: u8 = 0x00;
+const METHOD_USERPASS: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_FAILURE: u8 = 0x01;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// SOCKS5 inbound proxy (RFC 1928), accepting CONNECT requests and opening
+/// the requested target through `out`.
+pub struct Socks5Proxy<T, S>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
+    S: Spawner<T> + 'static,
+{
+    laddr: SocketAddr,
+    out: Arc<S>,
+    auth: Option<(String, String)>,
+    timeouts: Timeouts,
+    shaper: Arc<Shaper>,
+    _t: Option<T>,
+}
+
+impl<T, S> Socks5Proxy<T, S>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
+    S: Spawner<T> + 'static,
+{
+    pub fn new(out: S, laddr: SocketAddr) -> std::io::Result<Socks5Proxy<T, S>> {
+        let p = Socks5Proxy {
+            laddr,
+            out: Arc::new(out),
+            auth: None,
+            timeouts: Timeouts::default(),
+            shaper: Arc::new(Shaper::default()),
+            _t: None,
+        };
+        Ok(p)
+    }
+
+    /// Idle and lifetime limits of the forwarded connections.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Bandwidth limits of the forwarded connections, shared with every
+    /// proxy given the same shaper.
+    pub fn with_shaper(mut self, shaper: Arc<Shaper>) -> Self {
+        self.shaper = shaper;
+        self
+    }
+
+    /// Require username/password authentication (RFC 1929).
+    pub fn with_auth(mut self, username: &str, password: &str) -> Self {
+        self.auth = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    pub async fn run(self) -> io::Result<()> {
+        let lis = net::tcp_listen(self.laddr)?;
+
+        loop {
+            let (stream_in, raddr) = lis.accept().await?;
+            let span = info_span!("socks5", peer = %raddr);
+            debug!(parent: &span, "accept new conn");
+            let out = self.out.clone();
+            let auth = self.auth.clone();
+            let label = self.laddr.to_string();
+            let timeouts = self.timeouts;
+            let limit = self.shaper.limit(&label, Some(raddr.ip()));
+            tokio::task::spawn(
+                async move {
+                    if let Err(err) =
+                        Self::handle(out, auth, timeouts, limit, &label, raddr, stream_in).await
+                    {
+                        debug!("socks5 error: {}", err);
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    async fn handle(
+        out: Arc<S>,
+        auth: Option<(String, String)>,
+        timeouts: Timeouts,
+        limit: Limit,
+        label: &str,
+        raddr: SocketAddr,
+        mut stream: TcpStream,
+    ) -> io::Result<()> {
+        let request = async {
+            handshake(&auth, &mut stream).await?;
+            match read_request(&mut stream).await {
+                Ok(target) => Ok(target),
+                Err((rep, err)) => {
+                    reply(&mut stream, rep).await?;
+                    Err(err)
+                }
+            }
+        };
+        let target = tokio::time::timeout(HANDSHAKE_TIMEOUT, request)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "socks5 handshake timed out"))??;
+
+        let stream_out = match out.spawn_target(target.clone()).await {
+            Ok(stream_out) => stream_out,
+            Err(err) => {
+                let rep = match err.kind() {
+                    io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+                    io::ErrorKind::NotFound | io::ErrorKind::AddrNotAvailable => {
+                        REP_HOST_UNREACHABLE
+                    }
+                    _ => REP_FAILURE,
+                };
+                reply(&mut stream, rep).await?;
+                return Err(err);
+            }
+        };
+        reply(&mut stream, REP_SUCCEEDED).await?;
+
+        debug!(%target, "socks5 connected");
+        let mut conn =
+            tunnel::Tunnel::labeled(BiStream::new(stream), stream_out, label, Some(raddr))
+                .with_timeouts(timeouts)
+                .with_limit(limit);
+        let (a, b) = conn.copy().await?;
+        metrics::global().add_bytes(label, a, b);
+        debug!(sent = a, received = b, "conn closed");
+        Ok(())
+    }
+}
+
+impl<T, S> InBound for Socks5Proxy<T, S>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
+    S: Spawner<T> + 'static,
+{
+    fn recv(self: Box<Self>) -> BoxFuture<'static, io::Result<()>> {
+        Box::pin(self.run())
+    }
+}
+
+/// Negotiate the authentication method and, if configured, check the
+/// client's username and password.
+async fn handshake<IO>(auth: &Option<(String, String)>, stream: &mut IO) -> io::Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        return Err(invalid("unsupported socks version"));
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = if auth.is_some() {
+        METHOD_USERPASS
+    } else {
+        METHOD_NONE
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, METHOD_UNACCEPTABLE]).await?;
+        return Err(invalid("no acceptable auth method"));
+    }
+    stream.write_all(&[VERSION, method]).await?;
+
+    if let Some((username, password)) = auth {
+        let mut ver = [0u8; 2];
+        stream.read_exact(&mut ver).await?;
+        if ver[0] != AUTH_VERSION {
+            return Err(invalid("unsupported auth version"));
+        }
+        let mut uname = vec![0u8; ver[1] as usize];
+        stream.read_exact(&mut uname).await?;
+        let plen = stream.read_u8().await? as usize;
+        let mut passwd = vec![0u8; plen];
+        stream.read_exact(&mut passwd).await?;
+
+        let uname_ok = constant_time::verify_slices_are_equal(&uname, username.as_bytes()).is_ok();
+        let passwd_ok =
+            constant_time::verify_slices_are_equal(&passwd, password.as_bytes()).is_ok();
+        if !(uname_ok && passwd_ok) {
+            stream.write_all(&[AUTH_VERSION, 1]).await?;
+            return Err(invalid("authentication failed"));
+        }
+        stream.write_all(&[AUTH_VERSION, 0]).await?;
+    }
+    Ok(())
+}
+
+/// Read the CONNECT request, returning the reply code to send on error.
+async fn read_request<IO>(stream: &mut IO) -> Result<Target, (u8, io::Error)>
+where
+    IO: AsyncRead + Unpin,
+{
+    let mut head = [0u8; 4];
+    stream
+        .read_exact(&mut head)
+        .await
+        .map_err(|e| (REP_FAILURE, e))?;
+    if head[0] != VERSION {
+        return Err((REP_FAILURE, invalid("unsupported socks version")));
+    }
+    if head[1] != CMD_CONNECT {
+        return Err((REP_COMMAND_NOT_SUPPORTED, invalid("unsupported command")));
+    }
+
+    let read = async {
+        let target = match head[3] {
+            ATYP_IPV4 => {
+                let mut ip = [0u8; 4];
+                stream.read_exact(&mut ip).await?;
+                let port = stream.read_u16().await?;
+                Target::Addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+            }
+            ATYP_IPV6 => {
+                let mut ip = [0u8; 16];
+                stream.read_exact(&mut ip).await?;
+                let port = stream.read_u16().await?;
+                Target::Addr(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+            }
+            ATYP_DOMAIN => {
+                let len = stream.read_u8().await? as usize;
+                let mut host = vec![0u8; len];
+                stream.read_exact(&mut host).await?;
+                let port = stream.read_u16().await?;
+                let host = String::from_utf8(host).map_err(|_| invalid("invalid domain"))?;
+                Target::new(&host, port)?
+            }
+            _ => return Err(invalid("unsupported address type")),
+        };
+        Ok(target)
+    };
+    read.await.map_err(|e: io::Error| {
+        let rep = match e.kind() {
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => REP_ADDRESS_NOT_SUPPORTED,
+            _ => REP_FAILURE,
+        };
+        (rep, e)
+    })
+}
+
+async fn reply<IO>(stream: &mut IO, rep: u8) -> io::Result<()>
+where
+    IO: AsyncWrite + Unpin,
+{
+    // the outbound may be a tunnel, so there is no meaningful bound
+    // address to report
+    let buf = [VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+    stream.write_all(&buf).await
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Option<(String, String)> {
+        Some(("user".to_string(), "secret".to_string()))
+    }
+
+    async fn request(bytes: &[u8]) -> Result<Target, (u8, io::Error)> {
+        let mut stream = bytes;
+        read_request(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn negotiates_no_auth() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client
+            .write_all(&[VERSION, 2, METHOD_USERPASS, METHOD_NONE])
+            .await
+            .unwrap();
+        handshake(&None, &mut server).await.unwrap();
+        let mut answer = [0u8; 2];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, [VERSION, METHOD_NONE]);
+    }
+
+    #[tokio::test]
+    async fn rejects_unoffered_method() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[VERSION, 1, METHOD_NONE]).await.unwrap();
+        assert!(handshake(&auth(), &mut server).await.is_err());
+        let mut answer = [0u8; 2];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, [VERSION, METHOD_UNACCEPTABLE]);
+    }
+
+    #[tokio::test]
+    async fn checks_username_and_password() {
+        for (password, status) in [(&b"secret"[..], 0), (&b"wrong"[..], 1)] {
+            let (mut client, mut server) = tokio::io::duplex(64);
+            client
+                .write_all(&[VERSION, 1, METHOD_USERPASS])
+                .await
+                .unwrap();
+            client.write_all(&[AUTH_VERSION, 4]).await.unwrap();
+            client.write_all(b"user").await.unwrap();
+            client.write_all(&[password.len() as u8]).await.unwrap();
+            client.write_all(password).await.unwrap();
+            assert_eq!(handshake(&auth(), &mut server).await.is_ok(), status == 0);
+            let mut answer = [0u8; 4];
+            client.read_exact(&mut answer).await.unwrap();
+            assert_eq!(answer, [VERSION, METHOD_USERPASS, AUTH_VERSION, status]);
+        }
+    }
+
+    #[tokio::test]
+    async fn parses_ipv4() {
+        let target = request(&[VERSION, CMD_CONNECT, 0, ATYP_IPV4, 10, 0, 0, 1, 0x1f, 0x90])
+            .await
+            .unwrap();
+        assert_eq!(target, Target::Addr("10.0.0.1:8080".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn parses_ipv6() {
+        let mut bytes = vec![VERSION, CMD_CONNECT, 0, ATYP_IPV6];
+        bytes.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        bytes.extend(443u16.to_be_bytes());
+        let target = request(&bytes).await.unwrap();
+        assert_eq!(target, Target::Addr("[2001:db8::1]:443".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn parses_domain() {
+        let mut bytes = vec![VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, 11];
+        bytes.extend(b"example.com");
+        bytes.extend(443u16.to_be_bytes());
+        let target = request(&bytes).await.unwrap();
+        assert_eq!(target, Target::Host("example.com".to_string(), 443));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_requests() {
+        let (rep, _) = request(&[VERSION, 0x02, 0, ATYP_IPV4]).await.unwrap_err();
+        assert_eq!(rep, REP_COMMAND_NOT_SUPPORTED);
+        let (rep, _) = request(&[VERSION, CMD_CONNECT, 0, 0x09]).await.unwrap_err();
+        assert_eq!(rep, REP_ADDRESS_NOT_SUPPORTED);
+        let (rep, _) = request(&[
+            VERSION,
+            CMD_CONNECT,
+            0,
+            ATYP_DOMAIN,
+            3,
+            b'a',
+            b' ',
+            b'b',
+            0,
+            80,
+        ])
+        .await
+        .unwrap_err();
+        assert_eq!(rep, REP_ADDRESS_NOT_SUPPORTED);
+    }
+}
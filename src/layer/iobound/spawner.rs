@@ -1,13 +1,35 @@
-use std::net::SocketAddr;
+use std::sync::Arc;
 
 use super::io::BiStream;
-pub trait Spawner<T>
+use super::target::Target;
+
+/// Opens outbound streams. Methods take `&self` so one spawner, e.g. a
+/// single QUIC connection, can be shared by any number of inbound proxies
+/// and tasks behind an `Arc`.
+pub trait Spawner<T>: Send + Sync
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
 {
-    fn spawn(self) -> impl std::future::Future<Output = std::io::Result<BiStream<T>>> + Send;
+    fn spawn(&self) -> impl std::future::Future<Output = std::io::Result<BiStream<T>>> + Send;
     fn spawn_target(
-        self,
-        target: SocketAddr,
+        &self,
+        target: Target,
     ) -> impl std::future::Future<Output = std::io::Result<BiStream<T>>> + Send;
 }
+
+impl<T, S> Spawner<T> for Arc<S>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+    S: Spawner<T>,
+{
+    fn spawn(&self) -> impl std::future::Future<Output = std::io::Result<BiStream<T>>> + Send {
+        self.as_ref().spawn()
+    }
+
+    fn spawn_target(
+        &self,
+        target: Target,
+    ) -> impl std::future::Future<Output = std::io::Result<BiStream<T>>> + Send {
+        self.as_ref().spawn_target(target)
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use tokio::net::TcpStream;
+
+use crate::net;
+
+/// Where an outbound stream should connect to: either a literal socket
+/// address or a host name that is resolved when connecting.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Target {
+    Addr(SocketAddr),
+    Host(String, u16),
+}
+
+impl Target {
+    /// Build a target from a host (name or ip literal, IPv6 optionally in
+    /// brackets) and a port.
+    pub fn new(host: &str, port: u16) -> io::Result<Target> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Target::Addr(SocketAddr::new(ip, port)));
+        }
+        let valid = !host.is_empty()
+            && host.len() <= 253
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid host: {}", host),
+            ));
+        }
+        Ok(Target::Host(host.to_string(), port))
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Target::Addr(addr) => addr.port(),
+            Target::Host(_, port) => *port,
+        }
+    }
+
+    /// Look up every address of the target.
+    pub async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            Target::Addr(addr) => Ok(vec![*addr]),
+            Target::Host(host, port) => {
+                let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), *port))
+                    .await?
+                    .collect();
+                if addrs.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} has no address", host),
+                    ));
+                }
+                Ok(addrs)
+            }
+        }
+    }
+
+    /// Resolve the target and connect to it, racing A and AAAA results.
+    pub async fn connect(&self) -> io::Result<TcpStream> {
+        match self {
+            Target::Addr(addr) => net::tcp_socket(addr)?.connect(*addr).await,
+            Target::Host(_, _) => net::connect_any(self.resolve().await?).await,
+        }
+    }
+}
+
+impl From<SocketAddr> for Target {
+    fn from(addr: SocketAddr) -> Self {
+        Target::Addr(addr)
+    }
+}
+
+impl FromStr for Target {
+    type Err = io::Error;
+
+    /// Parse `host:port`, `ip:port` or `[ipv6]:port`.
+    fn from_str(s: &str) -> io::Result<Target> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Target::Addr(addr));
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) => match port.parse::<u16>() {
+                Ok(port) => Target::new(host, port),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid port in {}", s),
+                )),
+            },
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("missing port in {}", s),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Addr(addr) => write!(f, "{}", addr),
+            Target::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
@@ -1,56 +1,101 @@
-use tokio::net::TcpSocket;
-
 use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{debug, info_span, warn, Instrument};
 
 use super::io::BiStream;
+use super::shape::Shaper;
 use super::spawner::Spawner;
-use super::tunnel;
+use super::tunnel::{self, Timeouts};
+use crate::layer::{BoxFuture, InBound};
+use crate::metrics;
+use crate::net;
 
 pub struct TcpProxy<T, S>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
-    S: Spawner<T> + Copy + Send,
+    S: Spawner<T> + 'static,
 {
     laddr: SocketAddr,
-    out: S,
+    out: Arc<S>,
+    timeouts: Timeouts,
+    shaper: Arc<Shaper>,
     _t: Option<T>,
 }
 
 impl<T, S> TcpProxy<T, S>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
-    S: Spawner<T> + Copy + Send,
+    S: Spawner<T> + 'static,
 {
     pub fn new(out: S, laddr: SocketAddr) -> std::io::Result<TcpProxy<T, S>> {
         let p = TcpProxy {
             laddr: laddr,
-            out: out,
+            out: Arc::new(out),
+            timeouts: Timeouts::default(),
+            shaper: Arc::new(Shaper::default()),
             _t: None,
         };
         Ok(p)
     }
+
+    /// Idle and lifetime limits of the forwarded connections.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Bandwidth limits of the forwarded connections, shared with every
+    /// proxy given the same shaper.
+    pub fn with_shaper(mut self, shaper: Arc<Shaper>) -> Self {
+        self.shaper = shaper;
+        self
+    }
     pub async fn run(self) -> io::Result<()> {
-        let socket = TcpSocket::new_v4()?;
-        socket.bind(self.laddr)?;
-        let lis = socket.listen(1024)?;
+        let lis = net::tcp_listen(self.laddr)?;
 
         loop {
             let (stream_in, raddr) = lis.accept().await?;
-            println!("accept new conn: {}", raddr);
-            let stream_out = self.out.spawn().await?;
-            let stream_in = BiStream::new(stream_in);
-            let mut conn = tunnel::Tunnel::new(stream_in, stream_out);
-            tokio::task::spawn(async move {
-                match conn.copy().await {
-                    Ok((a, b)) => {
-                        println!("copy {}:{}", a, b)
-                    }
-                    Err(err) => {
-                        println!("{}", err)
+            let span = info_span!("tcp", peer = %raddr);
+            debug!(parent: &span, "accept new conn");
+            let out = self.out.clone();
+            let label = self.laddr.to_string();
+            let timeouts = self.timeouts;
+            let limit = self.shaper.limit(&label, Some(raddr.ip()));
+            tokio::task::spawn(
+                async move {
+                    let stream_out = match out.spawn().await {
+                        Ok(stream_out) => stream_out,
+                        Err(err) => {
+                            warn!("connect outbound error: {}", err);
+                            return;
+                        }
+                    };
+                    let stream_in = BiStream::new(stream_in);
+                    let mut conn =
+                        tunnel::Tunnel::labeled(stream_in, stream_out, &label, Some(raddr))
+                            .with_timeouts(timeouts)
+                            .with_limit(limit);
+                    match conn.copy().await {
+                        Ok((a, b)) => {
+                            metrics::global().add_bytes(&label, a, b);
+                            debug!(sent = a, received = b, "conn closed")
+                        }
+                        Err(err) => debug!("conn error: {}", err),
                     }
                 }
-            });
+                .instrument(span),
+            );
         }
     }
 }
+
+impl<T, S> InBound for TcpProxy<T, S>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin + Send + 'static,
+    S: Spawner<T> + 'static,
+{
+    fn recv(self: Box<Self>) -> BoxFuture<'static, io::Result<()>> {
+        Box::pin(self.run())
+    }
+}
@@ -1,30 +1,80 @@
+use std::io;
 use std::net::SocketAddr;
 
 use super::io::BiStream;
-use tokio::net::{TcpSocket, TcpStream};
+use tokio::net::TcpStream;
+
+use crate::layer::{BoxFuture, BoxIo, OutBound};
+use crate::net;
+use crate::pool::Dial;
 
 use super::spawner::Spawner;
+use super::target::Target;
+use super::tunnel::Timeouts;
 
 #[derive(Clone, Copy)]
 pub struct TcpOutStream {
     raddr: Option<SocketAddr>,
+    timeouts: Timeouts,
 }
 
 impl TcpOutStream {
     pub fn new(raddr: Option<SocketAddr>) -> Self {
-        Self { raddr }
+        Self {
+            raddr,
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// Dial within `timeouts.connect`.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
     }
 }
 
 impl Spawner<TcpStream> for TcpOutStream {
-    async fn spawn(self) -> std::io::Result<BiStream<TcpStream>> {
-        let raddr = self.raddr.unwrap();
-        let stream = TcpSocket::new_v4()?.connect(raddr).await?;
+    async fn spawn(&self) -> std::io::Result<BiStream<TcpStream>> {
+        let stream = self.dial().await?;
         return Ok(BiStream::new(stream));
     }
 
-    async fn spawn_target(self, target: SocketAddr) -> std::io::Result<BiStream<TcpStream>> {
-        let stream = TcpSocket::new_v4()?.connect(target).await?;
+    async fn spawn_target(&self, target: Target) -> std::io::Result<BiStream<TcpStream>> {
+        let stream = self.timeouts.connect(target.connect()).await?;
         return Ok(BiStream::new(stream));
     }
 }
+
+impl Dial for TcpOutStream {
+    type Conn = TcpStream;
+    type Stream = TcpStream;
+
+    async fn dial(&self) -> std::io::Result<TcpStream> {
+        let Some(raddr) = self.raddr else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tcp outbound has no target",
+            ));
+        };
+        self.timeouts
+            .connect(net::tcp_socket(&raddr)?.connect(raddr))
+            .await
+    }
+
+    async fn finish(&self, conn: TcpStream) -> std::io::Result<BiStream<TcpStream>> {
+        Ok(BiStream::new(conn))
+    }
+}
+
+impl OutBound for TcpOutStream {
+    fn send(&self, target: Option<Target>) -> BoxFuture<'_, std::io::Result<BiStream<BoxIo>>> {
+        Box::pin(async move {
+            let stream = match target {
+                Some(target) => self.spawn_target(target).await?,
+                None => self.spawn().await?,
+            };
+            let stream: BoxIo = Box::new(stream.into_inner());
+            Ok(BiStream::new(stream))
+        })
+    }
+}
@@ -1,8 +1,363 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tokio::time::Sleep;
 
 use super::io::BiStream;
+use super::shape::Limit;
+use crate::metrics;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn unix_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Live counters of one tunnel, updated while it copies. `sent` is what the
+/// inbound side wrote toward the outbound, `received` the way back; a
+/// packet is one read from either side.
+#[derive(Debug)]
+pub struct Stats {
+    pub id: u64,
+    pub label: String,
+    pub peer: Option<SocketAddr>,
+    pub started: SystemTime,
+    sent_bytes: AtomicU64,
+    received_bytes: AtomicU64,
+    sent_packets: AtomicU64,
+    received_packets: AtomicU64,
+    /// Unix time of the last read in milliseconds.
+    last_active: AtomicU64,
+}
+
+impl Stats {
+    fn new(label: String, peer: Option<SocketAddr>) -> Self {
+        let started = SystemTime::now();
+        Stats {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            label,
+            peer,
+            started,
+            sent_bytes: AtomicU64::new(0),
+            received_bytes: AtomicU64::new(0),
+            sent_packets: AtomicU64::new(0),
+            received_packets: AtomicU64::new(0),
+            last_active: AtomicU64::new(unix_millis(started)),
+        }
+    }
+
+    fn record(&self, sent: bool, n: usize) {
+        let (bytes, packets) = if sent {
+            (&self.sent_bytes, &self.sent_packets)
+        } else {
+            (&self.received_bytes, &self.received_packets)
+        };
+        bytes.fetch_add(n as u64, Ordering::Relaxed);
+        packets.fetch_add(1, Ordering::Relaxed);
+        self.last_active
+            .store(unix_millis(SystemTime::now()), Ordering::Relaxed);
+    }
+
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn last_active(&self) -> SystemTime {
+        UNIX_EPOCH + std::time::Duration::from_millis(self.last_active.load(Ordering::Relaxed))
+    }
+
+    /// A point in time copy of the counters.
+    pub fn snapshot(&self) -> ConnInfo {
+        let now = SystemTime::now();
+        let age = now.duration_since(self.started).unwrap_or_default();
+        let idle = now.duration_since(self.last_active()).unwrap_or_default();
+        let sent_bytes = self.sent_bytes();
+        let received_bytes = self.received_bytes();
+        let secs = age.as_secs_f64().max(1.0);
+        ConnInfo {
+            id: self.id,
+            label: self.label.clone(),
+            peer: self.peer,
+            started: unix_millis(self.started) / 1000,
+            last_active: self.last_active.load(Ordering::Relaxed) / 1000,
+            age: age.as_secs(),
+            idle: idle.as_secs(),
+            sent_bytes,
+            received_bytes,
+            sent_packets: self.sent_packets.load(Ordering::Relaxed),
+            received_packets: self.received_packets.load(Ordering::Relaxed),
+            sent_rate: (sent_bytes as f64 / secs) as u64,
+            received_rate: (received_bytes as f64 / secs) as u64,
+        }
+    }
+}
+
+/// One row of the connection table.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConnInfo {
+    pub id: u64,
+    pub label: String,
+    pub peer: Option<SocketAddr>,
+    /// Unix time the tunnel was opened, in seconds.
+    pub started: u64,
+    /// Unix time of the last traffic, in seconds.
+    pub last_active: u64,
+    /// Seconds since `started`.
+    pub age: u64,
+    /// Seconds since `last_active`.
+    pub idle: u64,
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+    pub sent_packets: u64,
+    pub received_packets: u64,
+    /// Average bytes per second since `started`.
+    pub sent_rate: u64,
+    pub received_rate: u64,
+}
+
+/// Every tunnel alive in this process, by id.
+#[derive(Default)]
+pub struct ConnTable {
+    conns: Mutex<HashMap<u64, Arc<Stats>>>,
+}
+
+impl ConnTable {
+    fn insert(&self, stats: Arc<Stats>) {
+        self.conns.lock().unwrap().insert(stats.id, stats);
+    }
+
+    fn remove(&self, id: u64) {
+        self.conns.lock().unwrap().remove(&id);
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Stats>> {
+        self.conns.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Snapshots of the open tunnels, oldest first.
+    pub fn list(&self) -> Vec<ConnInfo> {
+        let mut list: Vec<ConnInfo> = self
+            .conns
+            .lock()
+            .unwrap()
+            .values()
+            .map(|s| s.snapshot())
+            .collect();
+        list.sort_by_key(|c| c.id);
+        list
+    }
+}
+
+pub fn table() -> &'static ConnTable {
+    static TABLE: OnceLock<ConnTable> = OnceLock::new();
+    TABLE.get_or_init(ConnTable::default)
+}
+
+/// Counts what is read from `inner` into `stats`, reading no faster than
+/// `limit` allows.
+struct Counted<'a, T> {
+    inner: &'a mut T,
+    stats: &'a Stats,
+    sent: bool,
+    limit: &'a Limit,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<'a, T> Counted<'a, T> {
+    fn new(inner: &'a mut T, stats: &'a Stats, sent: bool, limit: &'a Limit) -> Self {
+        Counted {
+            inner,
+            stats,
+            sent,
+            limit,
+            sleep: None,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let allowed = loop {
+            if let Some(sleep) = this.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            match this.limit.allowance() {
+                Ok(allowed) => break allowed,
+                Err(wait) => this.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        };
+
+        let mut limited = buf.take(allowed);
+        ready!(Pin::new(&mut *this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        // the limited buffer filled the start of `buf`'s unfilled part
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+        if n > 0 {
+            this.stats.record(this.sent, n);
+            this.limit.consume(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<'_, T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Limits on how long a forwarded connection may take to set up and live.
+/// `None` disables a limit. By default only dialing is limited, so quiet
+/// connections stay open as they did before timeouts existed.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Close a tunnel nothing was read from in either direction for this
+    /// long.
+    pub idle: Option<Duration>,
+    /// Close a tunnel this long after it was opened, busy or not.
+    pub lifetime: Option<Duration>,
+    /// Give up dialing an outbound connection after this long.
+    pub connect: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            idle: None,
+            lifetime: None,
+            connect: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Run `dial` within the connect timeout.
+    pub async fn connect<T>(&self, dial: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+        let Some(limit) = self.connect else {
+            return dial.await;
+        };
+        match tokio::time::timeout(limit, dial).await {
+            Ok(res) => res,
+            Err(_) => {
+                Close::Connect.count();
+                Err(Close::Connect.error())
+            }
+        }
+    }
+
+    /// Resolves once `stats` has been idle or alive for too long.
+    async fn expired(&self, stats: &Stats) -> Close {
+        loop {
+            let now = SystemTime::now();
+            let mut wait: Option<Duration> = None;
+            if let Some(lifetime) = self.lifetime {
+                let age = now.duration_since(stats.started).unwrap_or_default();
+                if age >= lifetime {
+                    return Close::Lifetime;
+                }
+                wait = Some(lifetime - age);
+            }
+            if let Some(idle) = self.idle {
+                let quiet = now.duration_since(stats.last_active()).unwrap_or_default();
+                if quiet >= idle {
+                    return Close::Idle;
+                }
+                wait = Some(wait.map_or(idle - quiet, |w| w.min(idle - quiet)));
+            }
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => std::future::pending::<()>().await,
+            }
+        }
+    }
+}
+
+/// Why a tunnel was closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Close {
+    /// Both sides finished.
+    Eof,
+    /// Reading or writing either side failed.
+    Error,
+    Idle,
+    Lifetime,
+    /// The outbound connection was never made.
+    Connect,
+}
+
+impl Close {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Close::Eof => "eof",
+            Close::Error => "error",
+            Close::Idle => "idle_timeout",
+            Close::Lifetime => "lifetime",
+            Close::Connect => "connect_timeout",
+        }
+    }
+
+    fn count(&self) {
+        metrics::global()
+            .tunnels_closed
+            .with_label_values(&[self.name()])
+            .inc();
+    }
+
+    fn error(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, self.to_string())
+    }
+}
 
+impl fmt::Display for Close {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Close::Eof => "closed",
+            Close::Error => "io error",
+            Close::Idle => "idle timeout",
+            Close::Lifetime => "max lifetime reached",
+            Close::Connect => "connect timeout",
+        })
+    }
+}
+
+/// Copies between an inbound and an outbound stream. The tunnel is listed
+/// in the [`table`] from creation until it is dropped, with counters that
+/// move while [`Tunnel::copy`] runs.
 pub struct Tunnel<I, O>
 where
     I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -10,6 +365,10 @@ where
 {
     i: BiStream<I>,
     o: BiStream<O>,
+    stats: Arc<Stats>,
+    timeouts: Timeouts,
+    limit: Limit,
+    close: Option<Close>,
 }
 
 impl<I, O> Tunnel<I, O>
@@ -18,22 +377,73 @@ where
     O: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
     pub fn new(i: BiStream<I>, o: BiStream<O>) -> Self {
-        let conn = Self { i: i, o: o };
-        return conn;
+        Self::labeled(i, o, "", None)
     }
-    pub async fn copy(&mut self) -> Result<(u64, u64), std::io::Error> {
-        // let mut i = self.i.lock().unwrap();
-        // let mut o = self.o.lock().unwrap();
-        let x = tokio::io::copy_bidirectional(self.i.inner_mut(), self.o.inner_mut()).await;
-        match x {
-            Ok((a, b)) => {
-                return Ok((a, b));
-            }
-            Err(err) => {
-                return Err(err);
-            }
+
+    /// A tunnel listed under `label`, e.g. a service name or listen
+    /// address, and the address of the client it serves.
+    pub fn labeled(i: BiStream<I>, o: BiStream<O>, label: &str, peer: Option<SocketAddr>) -> Self {
+        let stats = Arc::new(Stats::new(label.to_string(), peer));
+        table().insert(stats.clone());
+        Self {
+            i,
+            o,
+            stats,
+            timeouts: Timeouts::default(),
+            limit: Limit::default(),
+            close: None,
         }
     }
+
+    /// Replace the default idle and lifetime limits of [`Tunnel::copy`].
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Read no faster than `limit` allows, see [`Shaper`](super::shape::Shaper).
+    pub fn with_limit(mut self, limit: Limit) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Why [`Tunnel::copy`] returned, once it has.
+    pub fn close_reason(&self) -> Option<Close> {
+        self.close
+    }
+
+    /// The live counters of this tunnel.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Copy until both sides are done, either fails, or a timeout hits; a
+    /// timeout is returned as a `TimedOut` error naming it.
+    pub async fn copy(&mut self) -> Result<(u64, u64), std::io::Error> {
+        let mut i = Counted::new(self.i.inner_mut(), &self.stats, true, &self.limit);
+        let mut o = Counted::new(self.o.inner_mut(), &self.stats, false, &self.limit);
+        let timeouts = self.timeouts;
+        let (close, res) = tokio::select! {
+            res = tokio::io::copy_bidirectional(&mut i, &mut o) => match res {
+                Ok(n) => (Close::Eof, Ok(n)),
+                Err(err) => (Close::Error, Err(err)),
+            },
+            close = timeouts.expired(&self.stats) => (close, Err(close.error())),
+        };
+        close.count();
+        self.close = Some(close);
+        res
+    }
+}
+
+impl<I, O> Drop for Tunnel<I, O>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    O: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    fn drop(&mut self) {
+        table().remove(self.stats.id);
+    }
 }
 
 // impl Future for Connection
@@ -1,11 +1,87 @@
 pub mod iobound;
 
-pub trait InBound {
-    fn recv();
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinSet;
+
+use iobound::io::BiStream;
+use iobound::spawner::Spawner;
+use iobound::target::Target;
+
+/// A byte stream carried between an inbound and an outbound.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> Io for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+pub type BoxIo = Box<dyn Io>;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Accepts client connections and hands each of them to the outbound it
+/// was built with.
+pub trait InBound: Send {
+    /// Run the accept loop until the listener or connection fails.
+    fn recv(self: Box<Self>) -> BoxFuture<'static, io::Result<()>>;
+}
+
+/// Opens streams toward a destination, the type-erased form of a
+/// [`Spawner`] so outbounds can be chosen at runtime.
+pub trait OutBound: Send + Sync {
+    /// Open a stream to `target`, or to the outbound's default destination
+    /// when `target` is `None`.
+    fn send(&self, target: Option<Target>) -> BoxFuture<'_, io::Result<BiStream<BoxIo>>>;
 }
 
-pub trait OutBound {
-    fn send();
+/// A shared outbound usable wherever an inbound expects a [`Spawner`].
+#[derive(Clone)]
+pub struct SharedOutBound(Arc<dyn OutBound>);
+
+impl SharedOutBound {
+    pub fn new<O: OutBound + 'static>(out: O) -> Self {
+        Self(Arc::new(out))
+    }
 }
 
-pub trait Proxy: InBound + OutBound {}
+impl Spawner<BoxIo> for SharedOutBound {
+    async fn spawn(&self) -> io::Result<BiStream<BoxIo>> {
+        self.0.send(None).await
+    }
+
+    async fn spawn_target(&self, target: Target) -> io::Result<BiStream<BoxIo>> {
+        self.0.send(Some(target)).await
+    }
+}
+
+/// A set of inbounds, each already paired with its outbound, run together.
+#[derive(Default)]
+pub struct Proxy {
+    inbounds: Vec<Box<dyn InBound>>,
+}
+
+impl Proxy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_inbound<I: InBound + 'static>(mut self, inbound: I) -> Self {
+        self.inbounds.push(Box::new(inbound));
+        self
+    }
+
+    /// Run every inbound, returning when the first of them stops.
+    pub async fn run(self) -> io::Result<()> {
+        let mut tasks = JoinSet::new();
+        for inbound in self.inbounds {
+            tasks.spawn(inbound.recv());
+        }
+        match tasks.join_next().await {
+            Some(Ok(res)) => res,
+            Some(Err(err)) => Err(io::Error::other(err)),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Abuse protection for the rendezvous server: token bucket rate limits
+//! and stateless cookies proving a frontend receives at its source address.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use ring::constant_time::verify_slices_are_equal;
+use ring::hmac;
+use ring::rand::SystemRandom;
+
+/// Sustained rate and burst of a token bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct Rate {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl Rate {
+    pub fn new(per_sec: f64, burst: f64) -> Self {
+        Rate { per_sec, burst }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Datagrams accepted from one source ip.
+    pub per_ip: Rate,
+    /// Connect requests brokered toward the backends of one fqdn.
+    pub per_fqdn: Rate,
+    /// Largest datagram sent in reply, candidates are dropped to fit.
+    pub max_response: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            per_ip: Rate::new(20.0, 40.0),
+            per_fqdn: Rate::new(5.0, 10.0),
+            max_response: 512,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token buckets per key, forgotten once full again.
+pub struct RateLimiter<K> {
+    rate: Rate,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(rate: Rate) -> Self {
+        RateLimiter {
+            rate,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token for `key`, false if its bucket is empty.
+    pub fn allow(&mut self, key: K) -> bool {
+        let now = Instant::now();
+        let rate = self.rate;
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: rate.burst,
+            last: now,
+        });
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate.per_sec).min(rate.burst);
+        bucket.last = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Drop buckets that have refilled completely.
+    pub fn prune(&mut self) {
+        let rate = self.rate;
+        self.buckets
+            .retain(|_, b| b.tokens + b.last.elapsed().as_secs_f64() * rate.per_sec < rate.burst);
+    }
+}
+
+/// Issues and checks cookies bound to a source address. A cookie stays
+/// valid for one to two windows; the key is random per process.
+pub struct CookieJar {
+    key: hmac::Key,
+    window: Duration,
+}
+
+impl CookieJar {
+    pub const LEN: usize = 16;
+
+    pub fn new(window: Duration) -> Self {
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .expect("system random source");
+        CookieJar { key, window }
+    }
+
+    pub fn issue(&self, addr: &SocketAddr) -> Vec<u8> {
+        self.cookie(addr, self.epoch())
+    }
+
+    pub fn verify(&self, addr: &SocketAddr, cookie: &[u8]) -> bool {
+        let epoch = self.epoch();
+        cookie.len() == Self::LEN
+            && [epoch, epoch.saturating_sub(1)]
+                .iter()
+                .any(|epoch| verify_slices_are_equal(&self.cookie(addr, *epoch), cookie).is_ok())
+    }
+
+    fn epoch(&self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        now.as_secs() / self.window.as_secs().max(1)
+    }
+
+    fn cookie(&self, addr: &SocketAddr, epoch: u64) -> Vec<u8> {
+        let mut ctx = hmac::Context::with_key(&self.key);
+        ctx.update(&epoch.to_be_bytes());
+        ctx.update(addr.to_string().as_bytes());
+        ctx.sign().as_ref()[..Self::LEN].to_vec()
+    }
+}
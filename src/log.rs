@@ -0,0 +1,42 @@
+//! Logging setup. Every role logs through `tracing`, with spans carrying
+//! the fqdn, peer address and stream id of the session a line belongs to.
+
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+/// The `log` section of `config.yaml`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LogConfig {
+    /// A level (`info`) or filter directives (`info,nnat::server=debug`).
+    #[serde(default = "default_level")]
+    pub level: String,
+    /// One JSON object per line instead of human readable text.
+    #[serde(default)]
+    pub json: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: default_level(),
+            json: false,
+        }
+    }
+}
+
+fn default_level() -> String {
+    "info".to_string()
+}
+
+/// Install the global subscriber. `RUST_LOG`, if set, wins over the
+/// configured level.
+pub fn init(config: &LogConfig) {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(config.level.as_str()));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if config.json {
+        builder.json().with_current_span(true).init();
+    } else {
+        builder.init();
+    }
+}
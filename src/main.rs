@@ -1,23 +1,34 @@
+pub mod acl;
+pub mod admin;
 pub mod backend;
+pub mod config;
 pub mod endpoint;
 pub mod frontend;
 pub mod layer;
+pub mod limit;
+pub mod log;
 pub mod message;
+pub mod metrics;
+pub mod net;
 pub mod pool;
 pub mod server;
+pub mod store;
 pub mod tls;
 pub mod tunnel;
 use clap::Parser;
 
 pub use backend::Backend;
+use config::Config;
 pub use frontend::Frontend;
-use layer::iobound::{http, tcpout};
 pub use server::StunServer;
 
 use std::error::Error;
+use std::time::Duration;
+use tracing::error;
 
 #[derive(Parser)]
 pub struct Cli {
+    /// Log at debug level, whatever the config says.
     #[arg(long)]
     pub debug: bool,
     #[arg(short)]
@@ -26,6 +37,8 @@ pub struct Cli {
     pub f: bool,
     #[arg(long)]
     pub stun: bool,
+    #[arg(long, default_value = "config.yaml")]
+    pub config: String,
 }
 
 pub enum CliKind {
@@ -55,37 +68,31 @@ impl Cli {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    // let args = Cli::parse();
-    // let stun_addr = "114.115.218.1:3440";
-    // let fqdn = "localhost";
-    // match args.kind() {
-    //     CliKind::StunServer => {
-    //         let mut s = StunServer::new("0.0.0.0:3440");
-    //         s.run()?;
-    //     }
-    //     CliKind::Backend => {
-    //         let laddr = "0.0.0.0:3441".parse().unwrap();
-    //         let be = Backend::new(fqdn, laddr, stun_addr);
-    //         be.run().await?;
-    //     }
-    //     CliKind::Frontend => loop {
-    //         let fb = Frontend::new(fqdn, "0.0.0.0:3442", stun_addr);
-    //         match fb.run().await {
-    //             Ok(_) => return Ok(()),
-    //             Err(err) => {
-    //                 println!("proxy error: {}", err)
-    //             }
-    //         }
-    //     },
-    //     CliKind::Unknown => {
-    //         println!("nothing run")
-    //     }
-    // }
-    let raddr = "127.0.0.1:8111".parse()?;
-    let out = tcpout::TcpOutStream::new(Some(raddr));
-    let laddr = "0.0.0.0:8110".parse()?;
-    let ht = http::TcpProxy::new(out, laddr)?;
-    // let tcp = tcpin::TcpProxy::new(out, laddr)?;
-    ht.run().await?;
+    let args = Cli::parse();
+    let config = Config::load(&args.config)?;
+    let mut log = config.log.clone();
+    if args.debug {
+        log.level = "debug".to_string();
+    }
+    log::init(&log);
+    match args.kind() {
+        CliKind::StunServer => config.server()?.run().await?,
+        CliKind::Backend => config.backend()?.run().await?,
+        CliKind::Frontend => loop {
+            match config.frontend()?.run().await {
+                Ok(_) => return Ok(()),
+                Err(err) => {
+                    error!("frontend error: {}", err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        },
+        CliKind::Unknown => {
+            if let Some(laddr) = config.metrics {
+                metrics::spawn(laddr);
+            }
+            config.proxy()?.run().await?;
+        }
+    }
     Ok(())
 }
@@ -1,6 +1,10 @@
 use crate::endpoint::Kind;
+use crate::metrics;
+use ring::hmac;
 use std::fmt;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
 pub struct FmtError(Box<dyn 'static + fmt::Display + Send + Sync>);
 
 impl std::error::Error for FmtError {}
@@ -29,6 +33,7 @@ pub enum MessageKind {
     Unknown = 0,
     Stun = 1,
     Conn = 2,
+    Peer = 3,
 }
 
 impl MessageKind {
@@ -36,15 +41,132 @@ impl MessageKind {
         return match b {
             1 => MessageKind::Stun,
             2 => MessageKind::Conn,
+            3 => MessageKind::Peer,
             _ => MessageKind::Unknown,
         };
     }
 }
 
+fn encode_addr(buf: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(4);
+            buf.extend(&ip.octets()[..]);
+        }
+        IpAddr::V6(ip) => {
+            buf.push(16);
+            buf.extend(&ip.octets()[..]);
+        }
+    }
+    buf.extend(addr.port().to_be_bytes());
+}
+
+fn decode_addr(buf: &[u8], cur: &mut usize) -> Result<SocketAddr, FmtError> {
+    let ip_size = *buf.get(*cur).ok_or(FmtError::new("size error"))? as usize;
+    let end = *cur + 1 + ip_size + 2;
+    if buf.len() < end {
+        return Err(FmtError::new("size error"));
+    }
+    let raw = &buf[*cur + 1..*cur + 1 + ip_size];
+    let ip = match ip_size {
+        4 => IpAddr::V4(Ipv4Addr::new(raw[0], raw[1], raw[2], raw[3])),
+        16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(raw);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(FmtError::new("ip format error")),
+    };
+    let port = u16::from_be_bytes([buf[end - 2], buf[end - 1]]);
+    *cur = end;
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn encode_fqdn(buf: &mut Vec<u8>, fqdn: &str) -> Result<(), FmtError> {
+    if fqdn.len() > u8::MAX as usize {
+        return Err(FmtError::new("fqdn too long"));
+    }
+    buf.push(fqdn.len() as u8);
+    buf.extend(fqdn.as_bytes());
+    Ok(())
+}
+
+fn decode_fqdn(buf: &[u8], cur: &mut usize) -> Result<String, FmtError> {
+    let size = *buf.get(*cur).ok_or(FmtError::new("size error"))? as usize;
+    let end = *cur + 1 + size;
+    if buf.len() < end {
+        return Err(FmtError::new("size error"));
+    }
+    let fqdn = String::from_utf8_lossy(&buf[*cur + 1..end]).to_string();
+    *cur = end;
+    Ok(fqdn)
+}
+
+fn encode_candidates(buf: &mut Vec<u8>, candidates: &[SocketAddr]) -> Result<(), FmtError> {
+    if candidates.len() > u8::MAX as usize {
+        return Err(FmtError::new("too many candidates"));
+    }
+    buf.push(candidates.len() as u8);
+    for addr in candidates {
+        encode_addr(buf, addr);
+    }
+    Ok(())
+}
+
+fn encode_bytes(buf: &mut Vec<u8>, data: &[u8]) -> Result<(), FmtError> {
+    if data.len() > u8::MAX as usize {
+        return Err(FmtError::new("field too long"));
+    }
+    buf.push(data.len() as u8);
+    buf.extend(data);
+    Ok(())
+}
+
+/// A length prefixed trailing field, empty if the sender left it out.
+fn decode_optional_bytes(buf: &[u8], cur: &mut usize) -> Result<Vec<u8>, FmtError> {
+    let size = match buf.get(*cur) {
+        Some(n) => *n as usize,
+        None => return Ok(Vec::new()),
+    };
+    let end = *cur + 1 + size;
+    if buf.len() < end {
+        return Err(FmtError::new("size error"));
+    }
+    let data = buf[*cur + 1..end].to_vec();
+    *cur = end;
+    Ok(data)
+}
+
+fn decode_candidates(buf: &[u8], cur: &mut usize) -> Result<Vec<SocketAddr>, FmtError> {
+    // candidates are optional, peers without any may omit the count
+    let n = match buf.get(*cur) {
+        Some(n) => *n as usize,
+        None => return Ok(Vec::new()),
+    };
+    *cur += 1;
+    let mut candidates = Vec::with_capacity(n);
+    for _ in 0..n {
+        candidates.push(decode_addr(buf, cur)?);
+    }
+    Ok(candidates)
+}
+
+/// Registration or connect request sent to the rendezvous server.
+///
+/// Layout: `kind | fqdn len | fqdn | candidate count | candidates
+/// [| cookie len | cookie [| token len | token]]`, where each candidate is
+/// `ip len | ip | port`.
 #[derive(Clone)]
 pub struct StunMessage {
     pub kind: Kind,
     pub fqdn: String,
+    /// Direct addresses (global IPv6) the sender can be reached on.
+    pub candidates: Vec<SocketAddr>,
+    /// Return-routability cookie handed out by the server and echoed back
+    /// by the frontend with its connect request.
+    pub cookie: Vec<u8>,
+    /// Access token a frontend identifies itself with, see [`crate::acl`].
+    pub token: String,
 }
 
 impl StunMessage {
@@ -52,12 +174,18 @@ impl StunMessage {
         return StunMessage {
             kind: Kind::Unknown,
             fqdn: String::default(),
+            candidates: Vec::new(),
+            cookie: Vec::new(),
+            token: String::new(),
         };
     }
     pub fn new(kind: Kind, fqdn: String) -> Self {
         return StunMessage {
             kind: kind,
             fqdn: fqdn,
+            candidates: Vec::new(),
+            cookie: Vec::new(),
+            token: String::new(),
         };
     }
 
@@ -65,10 +193,17 @@ impl StunMessage {
         if self.kind == Kind::Unknown {
             return Err(FmtError::new("kind error"));
         }
-        let mut buf = Vec::with_capacity(1 + self.fqdn.len() + 1);
+        let mut buf = Vec::with_capacity(4 + self.fqdn.len() + self.candidates.len() * 19);
         buf.push(MessageKind::Stun as u8);
         buf.push(self.kind as u8);
-        buf.extend(self.fqdn.as_bytes());
+        encode_fqdn(&mut buf, &self.fqdn)?;
+        encode_candidates(&mut buf, &self.candidates)?;
+        if !self.cookie.is_empty() || !self.token.is_empty() {
+            encode_bytes(&mut buf, &self.cookie)?;
+        }
+        if !self.token.is_empty() {
+            encode_bytes(&mut buf, self.token.as_bytes())?;
+        }
         return Ok(buf);
     }
     pub fn decode(&mut self, buf: &[u8]) -> Result<(), FmtError> {
@@ -78,24 +213,51 @@ impl StunMessage {
         if MessageKind::from(buf[0]) != MessageKind::Stun {
             return Err(FmtError::new("not stun message"));
         }
+        let mut cur = 2;
+        let fqdn = decode_fqdn(buf, &mut cur)?;
+        let candidates = decode_candidates(buf, &mut cur)?;
+        let cookie = decode_optional_bytes(buf, &mut cur)?;
+        let token = decode_optional_bytes(buf, &mut cur)?;
         self.kind = Kind::from(buf[1]);
-        let fqdn = String::from_utf8_lossy(&buf[2..]).to_string();
         self.fqdn = fqdn;
+        self.candidates = candidates;
+        self.cookie = cookie;
+        self.token = String::from_utf8_lossy(&token).to_string();
         return Ok(());
     }
 }
 
 impl std::fmt::Display for StunMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{{ {} {} }}", self.kind.to_string(), self.fqdn)
+        write!(
+            f,
+            "{{ {} {} {:?} }}",
+            self.kind.to_string(),
+            self.fqdn,
+            self.candidates
+        )
     }
 }
 
+/// Peer address handed out by the rendezvous server, or a punching probe
+/// exchanged directly between peers.
+///
+/// Layout: `kind | raddr | fqdn len | fqdn | candidate count | candidates
+/// [| token len | token [| nonce len | nonce]]`.
 #[derive(Clone, Debug)]
 pub struct ConnMessage {
     pub kind: Kind,
     pub fqdn: String,
     pub raddr: SocketAddr,
+    /// Direct addresses (global IPv6) the peer at `raddr` advertised.
+    pub candidates: Vec<SocketAddr>,
+    /// The access token of the frontend at `raddr`, passed on to the
+    /// backend with a connect request.
+    pub token: String,
+    /// Random value the server hands the backend with a connect request,
+    /// echoed back with the backend's punching socket so nobody else can
+    /// answer in its place.
+    pub nonce: Vec<u8>,
 }
 
 impl ConnMessage {
@@ -104,6 +266,9 @@ impl ConnMessage {
             kind: Kind::Unknown,
             fqdn: String::default(),
             raddr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+            candidates: Vec::new(),
+            token: String::new(),
+            nonce: Vec::new(),
         }
     }
     pub fn new(kind: Kind, raddr: SocketAddr, fqdn: String) -> Self {
@@ -111,30 +276,25 @@ impl ConnMessage {
             kind: kind,
             fqdn: fqdn,
             raddr: raddr,
+            candidates: Vec::new(),
+            token: String::new(),
+            nonce: Vec::new(),
         }
     }
     pub fn encode(self) -> Result<Vec<u8>, FmtError> {
-        let mut ip_size = 4;
-        if self.raddr.is_ipv6() {
-            ip_size = 16;
-        } else if !self.raddr.is_ipv4() {
-            return Err(FmtError::new("ip type error"));
-        }
-
-        let mut buf = Vec::with_capacity(1 + ip_size + 2 + self.fqdn.len());
+        let mut buf =
+            Vec::with_capacity(2 + 19 + 1 + self.fqdn.len() + 1 + self.candidates.len() * 19);
         buf.push(MessageKind::Conn as u8);
         buf.push(self.kind as u8);
-        buf.push(ip_size as u8);
-        match self.raddr.ip() {
-            std::net::IpAddr::V4(ip) => {
-                buf.extend(&ip.octets()[..]);
-            }
-            std::net::IpAddr::V6(ip) => {
-                buf.extend(&ip.octets()[..]);
-            }
+        encode_addr(&mut buf, &self.raddr);
+        encode_fqdn(&mut buf, &self.fqdn)?;
+        encode_candidates(&mut buf, &self.candidates)?;
+        if !self.token.is_empty() || !self.nonce.is_empty() {
+            encode_bytes(&mut buf, self.token.as_bytes())?;
+        }
+        if !self.nonce.is_empty() {
+            encode_bytes(&mut buf, &self.nonce)?;
         }
-        buf.extend(self.raddr.port().to_be_bytes());
-        buf.extend(self.fqdn.as_bytes());
         return Ok(buf);
     }
     pub fn decode(&mut self, buf: &[u8]) -> Result<(), FmtError> {
@@ -144,65 +304,18 @@ impl ConnMessage {
         if MessageKind::from(buf[0]) != MessageKind::Conn {
             return Err(FmtError::new("not conn message"));
         }
-        let mut cur = 1;
-        let kind = Kind::from(buf[cur]);
-        cur += 1;
-        let ip_size = buf[cur] as usize;
-
-        let ip: IpAddr = match ip_size {
-            4 => IpAddr::V4(Ipv4Addr::new(
-                buf[cur + 1],
-                buf[cur + 2],
-                buf[cur + 3],
-                buf[cur + 4],
-            )),
-            16 => {
-                let mut a = [0, 2];
-                a[0] = buf[cur + 1];
-                a[1] = buf[cur + 2];
-                let mut b = [0, 2];
-                b[0] = buf[cur + 3];
-                b[1] = buf[cur + 4];
-                let mut c = [0, 2];
-                c[0] = buf[cur + 5];
-                c[1] = buf[cur + 6];
-                let mut d = [0, 2];
-                d[0] = buf[cur + 7];
-                d[1] = buf[cur + 8];
-                let mut e = [0, 2];
-                e[0] = buf[cur + 9];
-                e[1] = buf[cur + 10];
-                let mut f = [0, 2];
-                f[0] = buf[cur + 11];
-                f[1] = buf[cur + 12];
-                let mut g = [0, 2];
-                g[0] = buf[cur + 13];
-                g[1] = buf[cur + 14];
-                let mut h = [0, 2];
-                h[0] = buf[cur + 15];
-                h[1] = buf[cur + 16];
-                IpAddr::V6(Ipv6Addr::new(
-                    u16::from_be_bytes(a),
-                    u16::from_be_bytes(b),
-                    u16::from_be_bytes(c),
-                    u16::from_be_bytes(d),
-                    u16::from_be_bytes(e),
-                    u16::from_be_bytes(f),
-                    u16::from_be_bytes(g),
-                    u16::from_be_bytes(h),
-                ))
-            }
-            _ => return Err(FmtError::new("ip format error")),
-        };
-        cur += ip_size;
-        let mut pb = [0; 2];
-        pb[0] = buf[cur + 1];
-        pb[1] = buf[cur + 2];
-        cur += 2;
-        let port = u16::from_be_bytes(pb);
-        self.kind = kind;
-        self.raddr = SocketAddr::new(ip, port);
-        self.fqdn = String::from_utf8_lossy(&buf[cur + 1..]).to_string();
+        let mut cur = 2;
+        let raddr = decode_addr(buf, &mut cur)?;
+        let fqdn = decode_fqdn(buf, &mut cur)?;
+        let candidates = decode_candidates(buf, &mut cur)?;
+        let token = decode_optional_bytes(buf, &mut cur)?;
+        let nonce = decode_optional_bytes(buf, &mut cur)?;
+        self.kind = Kind::from(buf[1]);
+        self.raddr = raddr;
+        self.fqdn = fqdn;
+        self.candidates = candidates;
+        self.token = String::from_utf8_lossy(&token).to_string();
+        self.nonce = nonce;
         return Ok(());
     }
 }
@@ -211,40 +324,354 @@ impl std::fmt::Display for ConnMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{{{} {} {} }}",
+            "{{{} {} {} {:?} }}",
             self.kind.to_string(),
             self.fqdn,
-            self.raddr.to_string()
+            self.raddr,
+            self.candidates
+        )
+    }
+}
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum PeerKind {
+    Unknown = 0,
+    /// The sender's own backend registrations.
+    Sync = 1,
+    /// A frontend request for a backend registered on the receiver;
+    /// `addr` is the frontend.
+    Forward = 2,
+    /// An answer the receiver should send on to the frontend at `addr`.
+    Deliver = 3,
+}
+
+impl PeerKind {
+    pub fn from(b: u8) -> Self {
+        match b {
+            1 => PeerKind::Sync,
+            2 => PeerKind::Forward,
+            3 => PeerKind::Deliver,
+            _ => PeerKind::Unknown,
+        }
+    }
+}
+
+/// A backend registration as shared between rendezvous servers.
+#[derive(Clone, Debug)]
+pub struct PeerEntry {
+    pub fqdn: String,
+    pub addr: SocketAddr,
+    /// Seconds since the backend was last seen.
+    pub age: u32,
+}
+
+/// Message exchanged between clustered rendezvous servers.
+///
+/// Layout: `kind | addr | sent (u64) | entry count (u16) | entries |
+/// payload`, each entry being `fqdn len | fqdn | addr | age (u32)`. The
+/// payload is a complete client message relayed as is. On the wire the
+/// message is [sealed](PeerMessage::seal) with the cluster's shared key.
+#[derive(Clone, Debug)]
+pub struct PeerMessage {
+    pub kind: PeerKind,
+    pub addr: SocketAddr,
+    /// Unix time in seconds the message was sent at, so a captured one
+    /// can not be replayed later.
+    pub sent: u64,
+    pub entries: Vec<PeerEntry>,
+    pub payload: Vec<u8>,
+}
+
+impl Default for PeerMessage {
+    fn default() -> Self {
+        PeerMessage {
+            kind: PeerKind::Unknown,
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+            sent: 0,
+            entries: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+}
+
+impl PeerMessage {
+    /// A message sent now.
+    pub fn new(kind: PeerKind, addr: SocketAddr) -> Self {
+        PeerMessage {
+            kind,
+            addr,
+            sent: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            ..PeerMessage::default()
+        }
+    }
+
+    pub fn encode(self) -> Result<Vec<u8>, FmtError> {
+        if self.kind == PeerKind::Unknown {
+            return Err(FmtError::new("kind error"));
+        }
+        if self.entries.len() > u16::MAX as usize {
+            return Err(FmtError::new("too many entries"));
+        }
+        let mut buf = Vec::with_capacity(2 + 19 + 8 + 2 + self.payload.len());
+        buf.push(MessageKind::Peer as u8);
+        buf.push(self.kind as u8);
+        encode_addr(&mut buf, &self.addr);
+        buf.extend(self.sent.to_be_bytes());
+        buf.extend((self.entries.len() as u16).to_be_bytes());
+        for entry in self.entries.iter() {
+            encode_fqdn(&mut buf, &entry.fqdn)?;
+            encode_addr(&mut buf, &entry.addr);
+            buf.extend(entry.age.to_be_bytes());
+        }
+        buf.extend(self.payload);
+        Ok(buf)
+    }
+
+    pub fn decode(&mut self, buf: &[u8]) -> Result<(), FmtError> {
+        if buf.len() <= 2 {
+            return Err(FmtError::new("size error"));
+        }
+        if MessageKind::from(buf[0]) != MessageKind::Peer {
+            return Err(FmtError::new("not peer message"));
+        }
+        let mut cur = 2;
+        let addr = decode_addr(buf, &mut cur)?;
+        if buf.len() < cur + 8 + 2 {
+            return Err(FmtError::new("size error"));
+        }
+        let mut sent = [0; 8];
+        sent.copy_from_slice(&buf[cur..cur + 8]);
+        cur += 8;
+        let n = u16::from_be_bytes([buf[cur], buf[cur + 1]]) as usize;
+        cur += 2;
+        let mut entries = Vec::with_capacity(n.min(64));
+        for _ in 0..n {
+            let fqdn = decode_fqdn(buf, &mut cur)?;
+            let addr = decode_addr(buf, &mut cur)?;
+            if buf.len() < cur + 4 {
+                return Err(FmtError::new("size error"));
+            }
+            let age = u32::from_be_bytes([buf[cur], buf[cur + 1], buf[cur + 2], buf[cur + 3]]);
+            cur += 4;
+            entries.push(PeerEntry { fqdn, addr, age });
+        }
+        self.kind = PeerKind::from(buf[1]);
+        self.addr = addr;
+        self.sent = u64::from_be_bytes(sent);
+        self.entries = entries;
+        self.payload = buf[cur..].to_vec();
+        Ok(())
+    }
+
+    /// Encode and append an HMAC-SHA256 tag over the message with `key`,
+    /// the secret shared by the members of a cluster.
+    pub fn seal(self, key: &hmac::Key) -> Result<Vec<u8>, FmtError> {
+        let mut buf = self.encode()?;
+        let tag = hmac::sign(key, &buf);
+        buf.extend(tag.as_ref());
+        Ok(buf)
+    }
+
+    /// Check the tag of a sealed message with `key` and decode it.
+    pub fn open(buf: &[u8], key: &hmac::Key) -> Result<PeerMessage, FmtError> {
+        if buf.len() < PEER_TAG_LEN {
+            return Err(FmtError::new("size error"));
+        }
+        let (data, tag) = buf.split_at(buf.len() - PEER_TAG_LEN);
+        hmac::verify(key, data, tag).map_err(|_| FmtError::new("bad peer message tag"))?;
+        let mut msg = PeerMessage::default();
+        msg.decode(data)?;
+        Ok(msg)
+    }
+}
+
+/// Size of the tag closing a sealed [`PeerMessage`].
+pub const PEER_TAG_LEN: usize = 32;
+
+impl std::fmt::Display for PeerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{ {:?} {} {} entries {} bytes }}",
+            self.kind,
+            self.addr,
+            self.entries.len(),
+            self.payload.len()
         )
     }
 }
+
 pub enum Message {
     Stun(StunMessage),
     Conn(ConnMessage),
+    Peer(PeerMessage),
     Unknown(Vec<u8>),
 }
 
 pub fn decode(buf: &[u8]) -> Message {
+    if buf.is_empty() {
+        return Message::Unknown(Vec::new());
+    }
     match MessageKind::from(buf[0]) {
         MessageKind::Conn => {
             let mut msg = ConnMessage::default();
-            if let Err(err) = msg.decode(&buf) {
-                print!("decode conn msg error: {}", err);
+            if let Err(err) = msg.decode(buf) {
+                debug!("decode conn msg error: {}", err);
+                metrics::global()
+                    .decode_errors
+                    .with_label_values(&["conn"])
+                    .inc();
                 return Message::Unknown(buf.to_vec());
             }
             return Message::Conn(msg);
         }
         MessageKind::Stun => {
             let mut msg = StunMessage::default();
-            if let Err(err) = msg.decode(&buf) {
-                println!("decode stun msg error: {}", err);
+            if let Err(err) = msg.decode(buf) {
+                debug!("decode stun msg error: {}", err);
+                metrics::global()
+                    .decode_errors
+                    .with_label_values(&["stun"])
+                    .inc();
                 return Message::Unknown(buf.to_vec());
             }
 
             return Message::Stun(msg);
         }
+        MessageKind::Peer => {
+            let mut msg = PeerMessage::default();
+            if let Err(err) = msg.decode(buf) {
+                debug!("decode peer msg error: {}", err);
+                metrics::global()
+                    .decode_errors
+                    .with_label_values(&["peer"])
+                    .inc();
+                return Message::Unknown(buf.to_vec());
+            }
+            Message::Peer(msg)
+        }
         _ => {
             return Message::Unknown(buf.to_vec());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn stun(buf: &[u8]) -> StunMessage {
+        let mut msg = StunMessage::default();
+        msg.decode(buf).unwrap();
+        msg
+    }
+
+    fn conn(buf: &[u8]) -> ConnMessage {
+        let mut msg = ConnMessage::default();
+        msg.decode(buf).unwrap();
+        msg
+    }
+
+    #[test]
+    fn stun_known_bytes() {
+        let mut msg = StunMessage::new(Kind::Backend, "a.io".to_string());
+        msg.candidates = vec![addr("1.2.3.4:80")];
+        let buf = msg.encode().unwrap();
+        assert_eq!(
+            buf,
+            [1, 2, 4, b'a', b'.', b'i', b'o', 1, 4, 1, 2, 3, 4, 0, 80]
+        );
+    }
+
+    #[test]
+    fn stun_trailing_fields() {
+        // an old peer stops after the fqdn
+        let msg = stun(&[1, 1, 4, b'a', b'.', b'i', b'o']);
+        assert_eq!(msg.kind, Kind::Frontend);
+        assert_eq!(msg.fqdn, "a.io");
+        assert!(msg.candidates.is_empty() && msg.cookie.is_empty() && msg.token.is_empty());
+
+        let mut msg = StunMessage::new(Kind::Frontend, "a.io".to_string());
+        msg.cookie = vec![7; 16];
+        let read = stun(&msg.clone().encode().unwrap());
+        assert_eq!(read.cookie, msg.cookie);
+        assert!(read.token.is_empty());
+
+        // a token without a cookie still takes the cookie's place
+        let mut msg = StunMessage::new(Kind::Frontend, "a.io".to_string());
+        msg.candidates = vec![addr("[2001:db8::1]:3441")];
+        msg.token = "secret".to_string();
+        let read = stun(&msg.clone().encode().unwrap());
+        assert_eq!(read.candidates, msg.candidates);
+        assert!(read.cookie.is_empty());
+        assert_eq!(read.token, "secret");
+    }
+
+    #[test]
+    fn stun_truncated() {
+        let mut msg = StunMessage::default();
+        assert!(msg.decode(&[1, 1, 4, b'a']).is_err());
+        assert!(msg.decode(&[1, 1, 1, b'a', 1, 4, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn conn_trailing_fields() {
+        let msg = ConnMessage::new(Kind::Backend, addr("1.2.3.4:80"), "a.io".to_string());
+        let buf = msg.encode().unwrap();
+        assert_eq!(
+            buf,
+            [2, 2, 4, 1, 2, 3, 4, 0, 80, 4, b'a', b'.', b'i', b'o', 0]
+        );
+        let read = conn(&buf[..buf.len() - 1]);
+        assert_eq!(read.raddr, addr("1.2.3.4:80"));
+        assert!(read.candidates.is_empty() && read.token.is_empty() && read.nonce.is_empty());
+
+        let mut msg = ConnMessage::new(Kind::Frontend, addr("[::1]:9"), "a.io".to_string());
+        msg.nonce = vec![1; 16];
+        let read = conn(&msg.clone().encode().unwrap());
+        assert_eq!(read.kind, Kind::Frontend);
+        assert!(read.token.is_empty());
+        assert_eq!(read.nonce, msg.nonce);
+
+        msg.token = "secret".to_string();
+        msg.candidates = vec![addr("[2001:db8::2]:3441")];
+        let read = conn(&msg.clone().encode().unwrap());
+        assert_eq!(read.token, "secret");
+        assert_eq!(read.candidates, msg.candidates);
+        assert_eq!(read.nonce, msg.nonce);
+    }
+
+    #[test]
+    fn peer_seal_open() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"cluster secret");
+        let mut msg = PeerMessage::new(PeerKind::Sync, addr("10.0.0.1:3440"));
+        msg.entries.push(PeerEntry {
+            fqdn: "a.io".to_string(),
+            addr: addr("1.2.3.4:5000"),
+            age: 7,
+        });
+        msg.payload = vec![1, 2, 3];
+        let buf = msg.clone().seal(&key).unwrap();
+
+        let read = PeerMessage::open(&buf, &key).unwrap();
+        assert_eq!(read.kind, PeerKind::Sync);
+        assert_eq!(read.addr, msg.addr);
+        assert_eq!(read.sent, msg.sent);
+        assert_eq!(read.entries.len(), 1);
+        assert_eq!(read.entries[0].fqdn, "a.io");
+        assert_eq!(read.entries[0].age, 7);
+        assert_eq!(read.payload, msg.payload);
+
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"another secret");
+        assert!(PeerMessage::open(&buf, &other).is_err());
+        let mut tampered = buf.clone();
+        tampered[3] ^= 1;
+        assert!(PeerMessage::open(&tampered, &key).is_err());
+        assert!(PeerMessage::open(&buf[..PEER_TAG_LEN - 1], &key).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Prometheus metrics shared by every role, served as text on `/metrics`
+//! next to the table of open tunnels on `/connections`.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use tracing::{error, info};
+
+use crate::layer::iobound::tunnel::{self, ConnInfo};
+
+pub struct Metrics {
+    registry: Registry,
+    /// Punching runs started, per role.
+    pub punch_attempts: IntCounterVec,
+    /// Punching runs that reached the peer, per role.
+    pub punch_successes: IntCounterVec,
+    /// Time from the first punch to the peer's answer, per role.
+    pub punch_seconds: HistogramVec,
+    pub quic_connections: IntGaugeVec,
+    pub quic_streams: IntGaugeVec,
+    /// Bytes carried per service and direction (`in` from the client side
+    /// of a tunnel, `out` toward it).
+    pub bytes: IntCounterVec,
+    /// Closed tunnels, per close reason.
+    pub tunnels_closed: IntCounterVec,
+    /// New backend registrations on the rendezvous server.
+    pub registrations: IntCounter,
+    /// Frontend connect requests on the rendezvous server, per outcome.
+    pub connect_requests: IntCounterVec,
+    /// Undecodable rendezvous messages, per message kind.
+    pub decode_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("nnat".to_string()), None)?;
+        let punch_attempts = IntCounterVec::new(
+            Opts::new("punch_attempts_total", "Hole punching runs started"),
+            &["role"],
+        )?;
+        let punch_successes = IntCounterVec::new(
+            Opts::new("punch_successes_total", "Hole punching runs that succeeded"),
+            &["role"],
+        )?;
+        let punch_seconds = HistogramVec::new(
+            HistogramOpts::new("punch_duration_seconds", "Time until a punch got through")
+                .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["role"],
+        )?;
+        let quic_connections = IntGaugeVec::new(
+            Opts::new("quic_connections", "Open QUIC connections"),
+            &["role"],
+        )?;
+        let quic_streams =
+            IntGaugeVec::new(Opts::new("quic_streams", "Open QUIC streams"), &["role"])?;
+        let bytes = IntCounterVec::new(
+            Opts::new("tunnel_bytes_total", "Bytes carried through tunnels"),
+            &["service", "direction"],
+        )?;
+        let tunnels_closed = IntCounterVec::new(
+            Opts::new("tunnels_closed_total", "Closed tunnels by reason"),
+            &["reason"],
+        )?;
+        let registrations = IntCounter::new(
+            "rendezvous_registrations_total",
+            "New backend registrations",
+        )?;
+        let connect_requests = IntCounterVec::new(
+            Opts::new(
+                "rendezvous_connect_requests_total",
+                "Frontend connect requests by outcome",
+            ),
+            &["outcome"],
+        )?;
+        let decode_errors = IntCounterVec::new(
+            Opts::new("decode_errors_total", "Undecodable rendezvous messages"),
+            &["kind"],
+        )?;
+        registry.register(Box::new(punch_attempts.clone()))?;
+        registry.register(Box::new(punch_successes.clone()))?;
+        registry.register(Box::new(punch_seconds.clone()))?;
+        registry.register(Box::new(quic_connections.clone()))?;
+        registry.register(Box::new(quic_streams.clone()))?;
+        registry.register(Box::new(bytes.clone()))?;
+        registry.register(Box::new(tunnels_closed.clone()))?;
+        registry.register(Box::new(registrations.clone()))?;
+        registry.register(Box::new(connect_requests.clone()))?;
+        registry.register(Box::new(decode_errors.clone()))?;
+        Ok(Metrics {
+            registry,
+            punch_attempts,
+            punch_successes,
+            punch_seconds,
+            quic_connections,
+            quic_streams,
+            bytes,
+            tunnels_closed,
+            registrations,
+            connect_requests,
+            decode_errors,
+        })
+    }
+
+    /// Count a punching run of `role` that got through after `elapsed`.
+    pub fn punched(&self, role: &str, elapsed: Duration) {
+        self.punch_successes.with_label_values(&[role]).inc();
+        self.punch_seconds
+            .with_label_values(&[role])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Count a finished tunnel's traffic toward `service`.
+    pub fn add_bytes(&self, service: &str, sent: u64, received: u64) {
+        self.bytes.with_label_values(&[service, "in"]).inc_by(sent);
+        self.bytes
+            .with_label_values(&[service, "out"])
+            .inc_by(received);
+    }
+
+    /// The text exposition of every metric.
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        let encoder = TextEncoder::new();
+        if let Err(err) = encoder.encode(&self.registry.gather(), &mut buf) {
+            return format!("# encode error: {}\n", err);
+        }
+        String::from_utf8_lossy(&buf).to_string()
+    }
+}
+
+pub fn global() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("metric definitions are valid"))
+}
+
+/// Holds a gauge up while alive, e.g. for an open connection.
+pub struct Active(IntGauge);
+
+impl Active {
+    pub fn new(gauge: IntGauge) -> Self {
+        gauge.inc();
+        Active(gauge)
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub fn connection(role: &str) -> Active {
+    Active::new(global().quic_connections.with_label_values(&[role]))
+}
+
+pub fn stream(role: &str) -> Active {
+    Active::new(global().quic_streams.with_label_values(&[role]))
+}
+
+pub async fn handler() -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            TextEncoder::new().format_type().to_string(),
+        )],
+        global().render(),
+    )
+}
+
+/// The open tunnels with their live counters, see
+/// [`tunnel::table`](crate::layer::iobound::tunnel::table).
+pub async fn connections() -> Json<Vec<ConnInfo>> {
+    Json(tunnel::table().list())
+}
+
+/// Serve `/metrics` and `/connections` on `laddr`.
+pub async fn serve(laddr: SocketAddr) -> io::Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(handler))
+        .route("/connections", get(connections));
+    let listener = tokio::net::TcpListener::bind(laddr).await?;
+    info!("metrics listen on {}", laddr);
+    axum::serve(listener, app).await
+}
+
+/// Serve `/metrics` on `laddr` in the background. Only the first call in a
+/// process starts a listener, so roles sharing a process or restarting
+/// their run loop don't fight over the port.
+pub fn spawn(laddr: SocketAddr) {
+    static SERVING: AtomicBool = AtomicBool::new(false);
+    if SERVING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    tokio::spawn(async move {
+        if let Err(err) = serve(laddr).await {
+            error!("metrics endpoint error: {}", err);
+        }
+    });
+}
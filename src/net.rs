@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::task::JoinSet;
+
+/// Create an unbound tcp socket matching the address family of `addr`.
+pub fn tcp_socket(addr: &SocketAddr) -> io::Result<TcpSocket> {
+    match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+}
+
+/// Bind a tcp listener on `laddr`. An unspecified IPv6 address (`[::]`)
+/// accepts IPv4 clients as well.
+pub fn tcp_listen(laddr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = tcp_socket(&laddr)?;
+    if let IpAddr::V6(ip) = laddr.ip() {
+        if ip.is_unspecified() {
+            SockRef::from(&socket).set_only_v6(false)?;
+        }
+    }
+    socket.bind(laddr)?;
+    socket.listen(1024)
+}
+
+/// Bind a udp socket on `laddr`, dual-stack when `laddr` is `[::]`.
+pub fn udp_bind(laddr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(laddr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let IpAddr::V6(ip) = laddr.ip() {
+        if ip.is_unspecified() {
+            socket.set_only_v6(false)?;
+        }
+    }
+    socket.bind(&laddr.into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Bind an ephemeral udp socket, dual-stack if the host supports IPv6.
+pub fn udp_bind_any() -> io::Result<UdpSocket> {
+    let v6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+    match udp_bind(v6) {
+        Ok(socket) => Ok(socket),
+        Err(_) => udp_bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)),
+    }
+}
+
+/// Split a comma separated list of addresses, ignoring empty items.
+pub fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_string())
+        .collect()
+}
+
+/// Strip the IPv4-mapped form a dual-stack socket reports for IPv4 peers.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Convert `addr` so it can be used as a destination from a socket bound
+/// to `local`: IPv4 targets are mapped when sending from an IPv6 socket.
+pub fn reachable_from(local: &SocketAddr, addr: SocketAddr) -> SocketAddr {
+    match (local, addr.ip()) {
+        (SocketAddr::V6(_), IpAddr::V4(ip)) => {
+            SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+/// Whether `ip` is a globally routable unicast address (2000::/3).
+pub fn is_global_v6(ip: &Ipv6Addr) -> bool {
+    ip.to_ipv4_mapped().is_none() && ip.segments()[0] & 0xe000 == 0x2000
+}
+
+/// Find the address the host would use for outgoing IPv6 traffic. No
+/// packets are sent; connecting a udp socket only selects a route.
+pub fn local_global_v6() -> Option<Ipv6Addr> {
+    let probe = std::net::UdpSocket::bind("[::]:0").ok()?;
+    probe.connect("[2001:4860:4860::8888]:53").ok()?;
+    match probe.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if is_global_v6(&ip) => Some(ip),
+        _ => None,
+    }
+}
+
+/// Direct-path candidates to advertise for `socket`, i.e. its global IPv6
+/// address when the socket can use IPv6.
+pub fn candidates(socket: &UdpSocket) -> Vec<SocketAddr> {
+    let laddr = match socket.local_addr() {
+        Ok(laddr) if laddr.is_ipv6() => laddr,
+        _ => return Vec::new(),
+    };
+    match local_global_v6() {
+        Some(ip) => vec![SocketAddr::new(IpAddr::V6(ip), laddr.port())],
+        None => Vec::new(),
+    }
+}
+
+/// Order the addresses a peer can be reached on. Global IPv6 candidates
+/// come first when we have a global IPv6 address ourselves, followed by the
+/// address observed by the rendezvous server.
+pub fn punch_targets(observed: SocketAddr, candidates: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut targets = Vec::with_capacity(candidates.len() + 1);
+    if local_global_v6().is_some() {
+        for addr in candidates {
+            if let IpAddr::V6(ip) = addr.ip() {
+                if is_global_v6(&ip) && !targets.contains(addr) {
+                    targets.push(*addr);
+                }
+            }
+        }
+    }
+    let observed = canonical(observed);
+    if !targets.contains(&observed) {
+        targets.push(observed);
+    }
+    targets
+}
+
+/// Resolve `host` (e.g. the rendezvous server address) into a destination
+/// usable from a socket bound to `local`.
+pub async fn resolve_from(local: &SocketAddr, host: &str) -> io::Result<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host(host).await?.collect();
+    if local.is_ipv4() {
+        addrs.retain(|addr| addr.is_ipv4());
+    }
+    match addrs.first() {
+        Some(addr) => Ok(reachable_from(local, *addr)),
+        None => Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("no usable address for {}", host),
+        )),
+    }
+}
+
+/// Delay before racing the next address, as recommended by RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to the first of `addrs` that answers, racing address families
+/// happy-eyeballs style: attempts alternate between IPv6 and IPv4 and a new
+/// one starts whenever the previous fails or stays pending for 250ms.
+pub async fn connect_any(addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6());
+    let mut queue = VecDeque::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => queue.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+    loop {
+        if let Some(addr) = queue.pop_front() {
+            attempts.spawn(async move { tcp_socket(&addr)?.connect(addr).await });
+        }
+        if attempts.is_empty() {
+            return Err(last_err.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrNotAvailable, "no address to connect")
+            }));
+        }
+        tokio::select! {
+            Some(res) = attempts.join_next() => match res {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(err)) => last_err = Some(err),
+                Err(err) => last_err = Some(io::Error::other(err)),
+            },
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if !queue.is_empty() => {}
+        }
+    }
+}
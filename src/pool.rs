@@ -1,37 +1,251 @@
-// use std::{collections::HashSet, hash::Hash};
-
-// pub struct Pool<T, S>
-// where
-//     T: Eq + Hash,
-//     S: Spawner<T>,
-// {
-//     data: HashSet<T>,
-//     spawner: S,
-// }
-
-// impl<T, S> Pool<T, S>
-// where
-//     T: Eq + Hash,
-//     S: Spawner<T>,
-// {
-//     pub fn new(spawner: S) -> Pool<T, S> {
-//         Pool {
-//             data: HashSet::new(),
-//             spawner: spawner,
-//         }
-//     }
-
-//     pub fn get(&mut self) -> T {
-//         for item in self.data {
-//             return item;
-//         }
-//         let item = self.spawner.spawn();
-
-//         self.data.insert(item);
-//         return item;
-//     }
-
-//     fn put(&mut self, item: T) {
-//         self.data.insert(item);
-//     }
-// }
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use tokio::io::ReadBuf;
+use tokio::net::TcpStream;
+use tracing::warn;
+
+use crate::layer::iobound::io::BiStream;
+use crate::layer::iobound::spawner::Spawner;
+use crate::layer::iobound::target::Target;
+use crate::layer::{BoxFuture, BoxIo, Io, OutBound};
+
+/// Streams that can tell whether an idle connection is still usable
+/// without consuming any of its data.
+pub trait Probe {
+    fn is_alive(&self) -> bool;
+}
+
+impl Probe for TcpStream {
+    fn is_alive(&self) -> bool {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut byte = [0u8; 1];
+        let mut buf = ReadBuf::new(&mut byte);
+        match self.poll_peek(&mut cx, &mut buf) {
+            // nothing to read yet, or a server-first protocol greeting
+            Poll::Pending => true,
+            Poll::Ready(Ok(n)) => n > 0,
+            Poll::Ready(Err(_)) => false,
+        }
+    }
+}
+
+/// Outbounds whose connections to the default destination can be dialed
+/// ahead of time. Dialing must not tell the far side anything beyond the
+/// connection itself; whatever does, like a stream header that makes it
+/// dial its own upstream, is left to `finish` once the connection is used.
+pub trait Dial: Send + Sync {
+    type Conn: Probe + Send + 'static;
+    type Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static;
+
+    fn dial(&self) -> impl Future<Output = io::Result<Self::Conn>> + Send;
+    fn finish(
+        &self,
+        conn: Self::Conn,
+    ) -> impl Future<Output = io::Result<BiStream<Self::Stream>>> + Send;
+
+    /// Another handle on `conn` if it can carry further streams once one
+    /// is opened on it, like a QUIC connection. Connections that carry a
+    /// single stream, like a tcp connection, are used once.
+    fn reuse(&self, _conn: &Self::Conn) -> Option<Self::Conn> {
+        None
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PreDialConfig {
+    /// Connections dialed ahead of time so new inbounds don't wait.
+    pub min_idle: usize,
+    /// Idle connections kept at most; extra ones are closed.
+    pub max_idle: usize,
+    /// Connections older than this are no longer handed out; streams
+    /// already open on a reused one are left running.
+    pub max_lifetime: Duration,
+    /// How often connections are checked and more are dialed.
+    pub check_interval: Duration,
+}
+
+impl Default for PreDialConfig {
+    fn default() -> Self {
+        PreDialConfig {
+            min_idle: 2,
+            max_idle: 8,
+            max_lifetime: Duration::from_secs(60),
+            check_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+struct Idle<C> {
+    conn: C,
+    created: Instant,
+}
+
+struct Inner<S: Dial> {
+    dialer: S,
+    config: PreDialConfig,
+    idle: Mutex<VecDeque<Idle<S::Conn>>>,
+    dialing: AtomicUsize,
+}
+
+/// Keeps connections to the default destination of an outbound dialed
+/// ahead of time, so new inbounds don't wait for a handshake.
+///
+/// `spawn` finishes an idle connection when one is available and dials a
+/// fresh one otherwise; `spawn_target` always dials since idle connections
+/// go to the default destination. Connections the outbound can
+/// [`reuse`](Dial::reuse) stay in the pool and carry the following streams
+/// in turn until they fail their [`Probe`] or reach `max_lifetime`; the
+/// others are used once.
+pub struct PreDial<S: Dial> {
+    inner: Arc<Inner<S>>,
+}
+
+impl<S: Dial> Clone for PreDial<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S> PreDial<S>
+where
+    S: Dial + 'static,
+{
+    /// Start dialing for `dialer`. Must be called inside a tokio runtime;
+    /// maintenance stops once every clone is dropped.
+    pub fn new(dialer: S, mut config: PreDialConfig) -> PreDial<S> {
+        config.max_idle = config.max_idle.max(config.min_idle);
+        let inner = Arc::new(Inner {
+            dialer,
+            config,
+            idle: Mutex::new(VecDeque::new()),
+            dialing: AtomicUsize::new(0),
+        });
+        tokio::spawn(Self::maintain(Arc::downgrade(&inner)));
+        PreDial { inner }
+    }
+
+    /// Number of idle connections currently held.
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    fn push(inner: &Inner<S>, conn: S::Conn, created: Instant) {
+        let mut idle = inner.idle.lock().unwrap();
+        if idle.len() < inner.config.max_idle {
+            idle.push_back(Idle { conn, created });
+        }
+    }
+
+    /// Hand out the oldest live connection, keeping it at the back of the
+    /// queue if it can be reused.
+    fn take(&self) -> Option<S::Conn> {
+        let mut idle = self.inner.idle.lock().unwrap();
+        while let Some(item) = idle.pop_front() {
+            if item.created.elapsed() < self.inner.config.max_lifetime && item.conn.is_alive() {
+                if let Some(conn) = self.inner.dialer.reuse(&item.conn) {
+                    idle.push_back(Idle {
+                        conn,
+                        created: item.created,
+                    });
+                }
+                return Some(item.conn);
+            }
+        }
+        None
+    }
+
+    /// Drop expired and dead idle connections.
+    fn evict(inner: &Inner<S>) {
+        let max_lifetime = inner.config.max_lifetime;
+        inner
+            .idle
+            .lock()
+            .unwrap()
+            .retain(|item| item.created.elapsed() < max_lifetime && item.conn.is_alive());
+    }
+
+    /// Dial until `min_idle` connections are idle or being dialed.
+    async fn fill(inner: Arc<Inner<S>>) {
+        loop {
+            let have = inner.idle.lock().unwrap().len() + inner.dialing.load(Ordering::Acquire);
+            if have >= inner.config.min_idle {
+                return;
+            }
+            inner.dialing.fetch_add(1, Ordering::AcqRel);
+            let res = inner.dialer.dial().await;
+            inner.dialing.fetch_sub(1, Ordering::AcqRel);
+            match res {
+                Ok(conn) => Self::push(&inner, conn, Instant::now()),
+                Err(err) => {
+                    warn!("pre-dial error: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn maintain(weak: Weak<Inner<S>>) {
+        loop {
+            let interval = match weak.upgrade() {
+                Some(inner) => {
+                    Self::evict(&inner);
+                    let interval = inner.config.check_interval;
+                    Self::fill(inner).await;
+                    interval
+                }
+                None => return,
+            };
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+impl<S> Spawner<S::Stream> for PreDial<S>
+where
+    S: Dial + Spawner<<S as Dial>::Stream> + 'static,
+{
+    async fn spawn(&self) -> io::Result<BiStream<S::Stream>> {
+        let conn = self.take();
+        tokio::spawn(Self::fill(self.inner.clone()));
+        let conn = match conn {
+            Some(conn) => conn,
+            None => {
+                let conn = self.inner.dialer.dial().await?;
+                if let Some(shared) = self.inner.dialer.reuse(&conn) {
+                    Self::push(&self.inner, shared, Instant::now());
+                }
+                conn
+            }
+        };
+        self.inner.dialer.finish(conn).await
+    }
+
+    async fn spawn_target(&self, target: Target) -> io::Result<BiStream<S::Stream>> {
+        self.inner.dialer.spawn_target(target).await
+    }
+}
+
+impl<S> OutBound for PreDial<S>
+where
+    S: Dial + Spawner<<S as Dial>::Stream> + 'static,
+    S::Stream: Io,
+{
+    fn send(&self, target: Option<Target>) -> BoxFuture<'_, io::Result<BiStream<BoxIo>>> {
+        Box::pin(async move {
+            let stream = match target {
+                Some(target) => self.spawn_target(target).await?,
+                None => self.spawn().await?,
+            };
+            let stream: BoxIo = Box::new(stream.into_inner());
+            Ok(BiStream::new(stream))
+        })
+    }
+}
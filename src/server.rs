@@ -1,111 +1,955 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
 
 use std::ops::Sub;
 use std::str::FromStr;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use std::net::UdpSocket;
+use ring::constant_time::verify_slices_are_equal;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use tracing::{debug, error, info, instrument, warn};
 
 use std::error::Error;
 
+use crate::acl::{Acl, Identity};
+use crate::admin;
 use crate::endpoint::Kind;
-use crate::message::{ConnMessage, StunMessage};
+use crate::limit::{CookieJar, Limits, RateLimiter};
+use crate::message::{self, ConnMessage, Message, PeerEntry, PeerKind, PeerMessage, StunMessage};
+use crate::metrics;
+use crate::net;
+use crate::store::{Snapshot, Store, StoredBackend};
 
-pub struct StunServer {
-    laddr: String,
-    backends: HashMap<String, HashMap<String, Duration>>,
+/// How long a backend control session stays registered without a
+/// keepalive.
+const REGISTRATION_TTL: Duration = Duration::from_secs(120);
+
+/// How long a frontend waits for the backend to answer a connect request.
+const PENDING_TTL: Duration = Duration::from_secs(30);
+
+/// Connect attempts kept for the admin API.
+const MAX_ATTEMPTS: usize = 256;
+
+/// How often a changed registry is written to the store.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often local registrations are sent to the other cluster members.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How far the clock of a cluster member may be off before its messages
+/// are dropped as replays.
+pub const PEER_MAX_AGE: Duration = Duration::from_secs(30);
+
+/// Size above which a sync is split over several datagrams.
+const SYNC_CHUNK: usize = 1200;
+
+/// How long a cookie handed to a frontend is accepted, one to two windows.
+const COOKIE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Size of the nonce a backend has to echo with its punching socket.
+const NONCE_LEN: usize = 16;
+
+/// How often full rate limit buckets are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Registration {
+    pub seen: Duration,
+    /// The cluster member the backend is registered with, `None` when it
+    /// is registered here.
+    pub via: Option<SocketAddr>,
 }
 
-impl StunServer {
-    pub fn new(laddr: &str) -> Self {
-        return StunServer {
-            laddr: laddr.to_string(),
-            backends: HashMap::new(),
-        };
+/// Where the answer for a frontend has to be sent.
+#[derive(Clone, Copy, Debug)]
+enum Route {
+    Direct(SocketAddr),
+    /// Through the cluster member the frontend asked.
+    Via(SocketAddr, SocketAddr),
+}
+
+/// Who to tell about a frontend's connect request.
+enum Notify {
+    /// The backend registered here, with the nonce it has to answer with.
+    Backend(SocketAddr, Vec<u8>),
+    /// The cluster member the backend is registered with.
+    Member(SocketAddr),
+}
+
+/// A frontend whose connect request was passed to a backend that has not
+/// reported its punching socket yet.
+struct Pending {
+    fqdn: String,
+    reply: Route,
+    seen: Duration,
+    attempt: u64,
+    /// What the backend has to send back with its punching socket.
+    nonce: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// No backend is registered for the fqdn.
+    NoBackend,
+    /// The fqdn or the frontend address is banned.
+    Banned,
+    /// Too many connect requests for the fqdn.
+    Limited,
+    /// The acl of the fqdn does not let the frontend in.
+    Denied,
+    /// The backend was asked for a punching socket.
+    Notified,
+    /// The request was passed to the cluster member the backend is
+    /// registered with.
+    Forwarded,
+    /// The backend answered and the frontend got its address.
+    Ready,
+    /// The backend did not answer in time.
+    Expired,
+}
+
+impl Outcome {
+    fn name(&self) -> &'static str {
+        match self {
+            Outcome::NoBackend => "no_backend",
+            Outcome::Banned => "banned",
+            Outcome::Limited => "limited",
+            Outcome::Denied => "denied",
+            Outcome::Notified => "notified",
+            Outcome::Forwarded => "forwarded",
+            Outcome::Ready => "ready",
+            Outcome::Expired => "expired",
+        }
     }
 
+    fn count(&self) {
+        metrics::global()
+            .connect_requests
+            .with_label_values(&[self.name()])
+            .inc();
+    }
+}
+
+/// One frontend connect request, as shown by the admin API.
+#[derive(Clone, Debug, Serialize)]
+pub struct Attempt {
+    pub id: u64,
+    pub fqdn: String,
+    pub frontend: SocketAddr,
+    pub backend: Option<SocketAddr>,
+    /// Unix time of the request in seconds.
+    pub at: u64,
+    pub outcome: Outcome,
+}
+
+/// Everything the rendezvous server knows, shared with the admin API.
+#[derive(Default)]
+pub struct Registry {
+    /// Backend control sessions per fqdn, keyed by their address.
+    pub backends: HashMap<String, HashMap<String, Registration>>,
+    /// Frontends waiting for a backend, keyed by their address.
+    pending: HashMap<SocketAddr, Pending>,
+    /// Most recent connect attempts, oldest first.
+    pub attempts: VecDeque<Attempt>,
+    next_attempt: u64,
+    pub banned_fqdns: HashSet<String>,
+    pub banned_ips: HashSet<IpAddr>,
+    pub acl: Acl,
+    /// Whether anything persisted changed since the last flush.
+    dirty: bool,
+    /// When the last snapshot was taken.
+    saved: Duration,
+}
+
+impl Registry {
     fn add_backend(&mut self, fqdn: String, raddr: SocketAddr, now: Duration) -> bool {
+        if self.is_banned(&fqdn, &raddr) {
+            return false;
+        }
         let key = fqdn.as_str();
         if !self.backends.contains_key(fqdn.clone().as_str()) {
             self.backends.insert(fqdn.clone(), HashMap::new());
         }
         if let Some(addrs) = self.backends.get_mut(key) {
-            addrs.insert(raddr.to_string(), now);
+            let reg = Registration {
+                seen: now,
+                via: None,
+            };
+            let old = addrs.insert(raddr.to_string(), reg);
+            if old.is_none_or(|old| old.via.is_some()) {
+                metrics::global().registrations.inc();
+                self.dirty = true;
+            }
+            // keepalives only need saving often enough that the stored
+            // registration outlives a restart
+            if now.saturating_sub(self.saved) >= REGISTRATION_TTL / 2 {
+                self.dirty = true;
+            }
             return true;
         }
         return false;
     }
-    fn get_backend(&mut self, fqdn: String, now: Duration) -> String {
-        let mut key = String::default();
-        let mut rm_keys = Vec::new();
-        if let Some(bs) = self.backends.get_mut(fqdn.clone().as_str()) {
-            for (k, v) in bs {
-                if now.sub(*v) > Duration::from_secs(120) {
-                    rm_keys.push(k.clone());
+
+    /// Record a registration another cluster member `via` reported,
+    /// unless this server has seen the backend more recently itself.
+    fn add_remote(&mut self, fqdn: String, raddr: SocketAddr, seen: Duration, via: SocketAddr) {
+        if self.is_banned(&fqdn, &raddr) {
+            return;
+        }
+        let addrs = self.backends.entry(fqdn).or_default();
+        let key = raddr.to_string();
+        if let Some(reg) = addrs.get(&key) {
+            if reg.seen >= seen {
+                return;
+            }
+        }
+        let via = Some(via);
+        addrs.insert(key, Registration { seen, via });
+    }
+
+    /// Control address of the most recently seen backend for `fqdn` and the
+    /// cluster member it is registered with, dropping registrations whose
+    /// keepalives stopped.
+    fn get_backend(
+        &mut self,
+        fqdn: String,
+        now: Duration,
+        local_only: bool,
+    ) -> Option<(SocketAddr, Option<SocketAddr>)> {
+        let bs = self.backends.get_mut(fqdn.as_str())?;
+        bs.retain(|_, v| now.sub(v.seen) <= REGISTRATION_TTL);
+        let (key, reg) = bs
+            .iter()
+            .filter(|(_, v)| !local_only || v.via.is_none())
+            .max_by_key(|(_, v)| v.seen)?;
+        let addr = SocketAddr::from_str(key.as_str()).ok()?;
+        Some((addr, reg.via))
+    }
+
+    /// Registrations made with this server, as sent to cluster members.
+    fn local_entries(&self, now: Duration) -> Vec<PeerEntry> {
+        let mut entries = Vec::new();
+        for (fqdn, bs) in self.backends.iter() {
+            for (addr, reg) in bs.iter() {
+                if reg.via.is_some() || now.sub(reg.seen) > REGISTRATION_TTL {
+                    continue;
+                }
+                let Ok(addr) = SocketAddr::from_str(addr) else {
+                    continue;
+                };
+                let age = now.saturating_sub(reg.seen).as_secs() as u32;
+                let fqdn = fqdn.clone();
+                entries.push(PeerEntry { fqdn, addr, age });
+            }
+        }
+        entries
+    }
+
+    /// Drop the registrations of `fqdn`, only the one from `addr` if given.
+    /// Returns how many were removed.
+    pub fn evict(&mut self, fqdn: &str, addr: Option<&str>) -> usize {
+        self.dirty = true;
+        match addr {
+            Some(addr) => {
+                let Some(bs) = self.backends.get_mut(fqdn) else {
+                    return 0;
+                };
+                let n = bs.remove(addr).map_or(0, |_| 1);
+                if bs.is_empty() {
+                    self.backends.remove(fqdn);
+                }
+                n
+            }
+            None => self.backends.remove(fqdn).map_or(0, |bs| bs.len()),
+        }
+    }
+
+    pub fn ban_fqdn(&mut self, fqdn: &str) {
+        self.evict(fqdn, None);
+        self.banned_fqdns.insert(fqdn.to_string());
+        self.dirty = true;
+    }
+
+    pub fn unban_fqdn(&mut self, fqdn: &str) -> bool {
+        self.dirty = true;
+        self.banned_fqdns.remove(fqdn)
+    }
+
+    pub fn ban_ip(&mut self, ip: IpAddr) {
+        let ip = ip.to_canonical();
+        for bs in self.backends.values_mut() {
+            bs.retain(|addr, _| {
+                SocketAddr::from_str(addr).map_or(true, |addr| addr.ip().to_canonical() != ip)
+            });
+        }
+        self.backends.retain(|_, bs| !bs.is_empty());
+        self.banned_ips.insert(ip);
+        self.dirty = true;
+    }
+
+    pub fn unban_ip(&mut self, ip: IpAddr) -> bool {
+        self.dirty = true;
+        self.banned_ips.remove(&ip.to_canonical())
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        for (fqdn, bs) in self.backends.iter() {
+            for (addr, reg) in bs.iter() {
+                // members of a cluster share theirs again after a restart
+                if reg.via.is_some() {
                     continue;
                 }
+                snapshot.backends.push(StoredBackend {
+                    fqdn: fqdn.clone(),
+                    addr: addr.clone(),
+                    seen: reg.seen.as_secs(),
+                });
+            }
+        }
+        snapshot.banned_fqdns = self.banned_fqdns.iter().cloned().collect();
+        snapshot.banned_ips = self.banned_ips.iter().cloned().collect();
+        snapshot.acl = self.acl.rules();
+        snapshot
+    }
 
-                key = k.into();
-                break;
+    /// Load a snapshot, skipping registrations that expired while the
+    /// server was down. Acl rules given to the server win over stored ones.
+    pub fn restore(&mut self, snapshot: Snapshot, now: Duration) -> Result<(), String> {
+        self.banned_fqdns.extend(snapshot.banned_fqdns);
+        self.banned_ips.extend(snapshot.banned_ips);
+        match self.acl.is_empty() {
+            true => self.acl = Acl::from_rules(&snapshot.acl)?,
+            false => self.dirty = self.acl.rules() != snapshot.acl,
+        }
+        for b in snapshot.backends {
+            let seen = Duration::from_secs(b.seen);
+            if now.saturating_sub(seen) > REGISTRATION_TTL {
+                continue;
             }
+            let Ok(addr) = SocketAddr::from_str(&b.addr) else {
+                continue;
+            };
+            if self.is_banned(&b.fqdn, &addr) {
+                continue;
+            }
+            self.backends
+                .entry(b.fqdn)
+                .or_default()
+                .insert(b.addr, Registration { seen, via: None });
+        }
+        Ok(())
+    }
+
+    /// The snapshot to write if anything changed since the last call.
+    fn take_dirty(&mut self, now: Duration) -> Option<Snapshot> {
+        if !self.dirty {
+            return None;
         }
-        if let Some(bs) = self.backends.get_mut(fqdn.clone().as_str()) {
-            bs.remove(key.as_str());
-            for k in rm_keys {
-                bs.remove(k.as_str());
+        self.dirty = false;
+        self.saved = now;
+        Some(self.snapshot())
+    }
+
+    fn is_banned(&self, fqdn: &str, addr: &SocketAddr) -> bool {
+        self.banned_fqdns.contains(fqdn) || self.banned_ips.contains(&addr.ip().to_canonical())
+    }
+
+    fn start_attempt(
+        &mut self,
+        fqdn: &str,
+        frontend: SocketAddr,
+        backend: Option<SocketAddr>,
+        now: Duration,
+        outcome: Outcome,
+    ) -> u64 {
+        let id = self.next_attempt;
+        self.next_attempt += 1;
+        if self.attempts.len() == MAX_ATTEMPTS {
+            self.attempts.pop_front();
+        }
+        self.attempts.push_back(Attempt {
+            id,
+            fqdn: fqdn.to_string(),
+            frontend,
+            backend,
+            at: now.as_secs(),
+            outcome,
+        });
+        outcome.count();
+        id
+    }
+
+    fn set_outcome(&mut self, id: u64, outcome: Outcome) {
+        if let Some(attempt) = self.attempts.iter_mut().rev().find(|a| a.id == id) {
+            if attempt.outcome != outcome {
+                attempt.outcome = outcome;
+                outcome.count();
+            }
+        }
+    }
+
+    fn expire_pending(&mut self, now: Duration) {
+        let mut expired = Vec::new();
+        self.pending.retain(|_, p| {
+            if now.sub(p.seen) <= PENDING_TTL {
+                return true;
             }
+            expired.push(p.attempt);
+            false
+        });
+        for id in expired {
+            if let Some(attempt) = self.attempts.iter_mut().find(|a| a.id == id) {
+                if attempt.outcome == Outcome::Notified {
+                    attempt.outcome = Outcome::Expired;
+                    Outcome::Expired.count();
+                }
+            }
+        }
+    }
+}
+
+pub struct StunServer {
+    laddr: String,
+    admin: Option<SocketAddr>,
+    /// Bearer token the admin API wants for every call.
+    admin_token: Option<String>,
+    metrics: Option<SocketAddr>,
+    store: Option<Arc<Store>>,
+    peers: Vec<String>,
+    /// Key authenticating messages between cluster members.
+    peer_key: Option<hmac::Key>,
+    registry: Arc<Mutex<Registry>>,
+    limits: Limits,
+    per_ip: RateLimiter<IpAddr>,
+    per_fqdn: Mutex<RateLimiter<String>>,
+    cookies: CookieJar,
+}
+
+impl StunServer {
+    pub fn new(laddr: &str) -> Self {
+        StunServer {
+            laddr: laddr.to_string(),
+            admin: None,
+            admin_token: None,
+            metrics: None,
+            store: None,
+            peers: Vec::new(),
+            peer_key: None,
+            registry: Arc::new(Mutex::new(Registry::default())),
+            limits: Limits::default(),
+            per_ip: RateLimiter::new(Limits::default().per_ip),
+            per_fqdn: Mutex::new(RateLimiter::new(Limits::default().per_fqdn)),
+            cookies: CookieJar::new(COOKIE_WINDOW),
         }
+    }
+
+    /// Only broker connects the acl lets in, see [`crate::acl`].
+    pub fn with_acl(self, acl: Acl) -> Self {
+        self.registry.lock().unwrap().acl = acl;
+        self
+    }
+
+    /// Replace the default rate limits and response size cap.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.per_ip = RateLimiter::new(limits.per_ip);
+        self.per_fqdn = Mutex::new(RateLimiter::new(limits.per_fqdn));
+        self.limits = limits;
+        self
+    }
+
+    /// Serve the admin HTTP API on `laddr`, a loopback address unless a
+    /// token is set with [`with_admin_token`](Self::with_admin_token).
+    pub fn with_admin(mut self, laddr: SocketAddr) -> Self {
+        self.admin = Some(laddr);
+        self
+    }
+
+    /// Require `token` as bearer token for every admin API call.
+    pub fn with_admin_token(mut self, token: &str) -> Self {
+        self.admin_token = Some(token.to_string());
+        self
+    }
+
+    /// Serve Prometheus metrics on `laddr`. The admin API, if enabled,
+    /// serves them too.
+    pub fn with_metrics(mut self, laddr: SocketAddr) -> Self {
+        self.metrics = Some(laddr);
+        self
+    }
+
+    /// Keep registrations and bans in the file at `path` across restarts.
+    pub fn with_store(mut self, path: &str) -> Self {
+        self.store = Some(Arc::new(Store::new(path)));
+        self
+    }
+
+    /// Share registrations with the rendezvous server at `addr`, which has
+    /// to list this server as a peer too. Needs [`with_peer_secret`].
+    ///
+    /// [`with_peer_secret`]: Self::with_peer_secret
+    pub fn with_peer(mut self, addr: &str) -> Self {
+        self.peers.push(addr.to_string());
+        self
+    }
 
-        return key;
+    /// Authenticate messages between cluster members with `secret`, which
+    /// every member has to share. Messages from peers without it, or older
+    /// than [`PEER_MAX_AGE`], are dropped.
+    pub fn with_peer_secret(mut self, secret: &str) -> Self {
+        self.peer_key = Some(hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()));
+        self
     }
 
-    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+    pub async fn run(&mut self) -> Result<(), Box<dyn Error>> {
         let laddr = self.laddr.clone();
-        let socket = UdpSocket::bind(laddr)?;
+        let socket = Arc::new(UdpSocket::bind(laddr).await?);
+        let local = socket.local_addr()?;
+
+        let mut peers = Vec::new();
+        for peer in self.peers.iter() {
+            peers.push(net::canonical(net::resolve_from(&local, peer).await?));
+        }
+        // no key only happens without peers, which never receive a message
+        let peer_key = match (&self.peer_key, peers.is_empty()) {
+            (Some(key), _) => key.clone(),
+            (None, true) => hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                .map_err(|_| "system random source")?,
+            (None, false) => return Err("cluster peers need a shared secret".into()),
+        };
+        if !peers.is_empty() {
+            let socket = socket.clone();
+            let registry = self.registry.clone();
+            let peers = peers.clone();
+            let key = peer_key.clone();
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(SYNC_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    if let Err(err) = Self::sync(&socket, &registry, &peers, &key).await {
+                        warn!("sync registrations error: {}", err);
+                    }
+                }
+            });
+        }
+
+        if let Some(store) = self.store.clone() {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let snapshot = store.load()?;
+            self.registry.lock().unwrap().restore(snapshot, now)?;
+            let registry = self.registry.clone();
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                    let snapshot = registry.lock().unwrap().take_dirty(now);
+                    let Some(snapshot) = snapshot else {
+                        continue;
+                    };
+                    let store = store.clone();
+                    let res = tokio::task::spawn_blocking(move || store.save(&snapshot)).await;
+                    match res {
+                        Ok(Ok(())) => {}
+                        Ok(Err(err)) => error!("save registry error: {}", err),
+                        Err(err) => error!("save registry task error: {}", err),
+                    }
+                }
+            });
+        }
+
+        if let Some(admin_addr) = self.admin {
+            let listener = admin::listen(admin_addr, self.admin_token.as_deref())?;
+            let registry = self.registry.clone();
+            let token = self.admin_token.clone();
+            tokio::spawn(async move {
+                if let Err(err) = admin::serve(listener, registry, token).await {
+                    error!("admin api error: {}", err);
+                }
+            });
+        }
+
+        if let Some(laddr) = self.metrics {
+            metrics::spawn(laddr);
+        }
 
         let mut buf = [0u8; 1500];
+        let mut pruned = Instant::now();
         loop {
-            let (n, raddr) = socket.recv_from(&mut buf)?;
-            if n > 0 {
-                let mut msg = StunMessage::default();
-                _ = msg.decode(&buf[..n])?;
-                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                match msg.kind {
+            let (n, from) = match socket.recv_from(&mut buf).await {
+                Ok(recv) => recv,
+                Err(err) => {
+                    warn!("recv udp message error: {}", err);
+                    continue;
+                }
+            };
+            let raddr = net::canonical(from);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            if pruned.elapsed() >= PRUNE_INTERVAL {
+                self.per_ip.prune();
+                self.per_fqdn.lock().unwrap().prune();
+                pruned = Instant::now();
+            }
+            // cluster members are trusted, everyone else is limited
+            if !peers.contains(&raddr) && !self.per_ip.allow(raddr.ip()) {
+                continue;
+            }
+            match message::decode(&buf[..n]) {
+                Message::Stun(msg) => match msg.kind {
                     Kind::Unknown => {}
                     Kind::Stun => {}
                     Kind::Frontend => {
-                        let fqdn = msg.fqdn.clone();
-                        println!("recv from fontend: {}, fqdn: {}", raddr.to_string(), fqdn);
-                        let baddr = self.get_backend(fqdn.clone(), now);
-                        if baddr == "" {
-                            println!("{} have no backend", fqdn);
+                        debug!(fqdn = %msg.fqdn, peer = %raddr, "recv from frontend");
+                        if !self.cookies.verify(&raddr, &msg.cookie) {
+                            self.challenge(&socket, &msg, from, n).await;
                             continue;
                         }
-                        let baddr = SocketAddr::from_str(baddr.as_str())?;
-                        let msg = ConnMessage::new(Kind::Backend, baddr, fqdn);
-                        let data = msg.clone().encode()?;
-                        if let Err(err) = socket.send_to(&data, raddr.clone()) {
-                            println!("send udp conn message err:{}", err)
-                        }
-                        let mut msg = msg.clone();
-                        msg.raddr = raddr.clone();
-                        let data = msg.encode()?;
-                        if let Err(err) = socket.send_to(&data, baddr) {
-                            println!("send udp conn message err:{}", err)
+                        let reply = Route::Direct(from);
+                        if let Err(err) = self
+                            .frontend_request(&socket, msg, raddr, reply, now, &peer_key)
+                            .await
+                        {
+                            warn!("frontend request from {} error: {}", raddr, err);
                         }
                     }
                     Kind::Backend => {
                         let fqdn = msg.fqdn.clone();
-                        println!("recv from backend: {}, fqdn: {}", raddr.to_string(), fqdn);
-                        if self.add_backend(fqdn, raddr, now) {
-                            let msg = StunMessage::new(Kind::Stun, msg.fqdn.clone());
-                            let data = msg.encode()?;
-                            socket.send_to(&data, raddr)?;
+                        debug!(fqdn = %fqdn, peer = %raddr, "recv from backend");
+                        // registrations are challenged like connect requests
+                        // so nobody can register an address they do not own
+                        if !self.cookies.verify(&raddr, &msg.cookie) {
+                            self.challenge(&socket, &msg, from, n).await;
+                            continue;
+                        }
+                        let added = self.registry.lock().unwrap().add_backend(fqdn, raddr, now);
+                        if added {
+                            info!(fqdn = %msg.fqdn, peer = %raddr, "backend registered");
+                            // a fresh cookie keeps the next keepalives valid
+                            let mut msg = StunMessage::new(Kind::Stun, msg.fqdn.clone());
+                            msg.cookie = self.cookies.issue(&raddr);
+                            if let Err(err) = send(&socket, msg.encode(), from).await {
+                                warn!("send registration ack to {} error: {}", raddr, err);
+                            }
+                        }
+                    }
+                },
+                Message::Conn(msg) => {
+                    if msg.kind != Kind::Backend {
+                        continue;
+                    }
+                    let Some(reply) = self.backend_ready(&msg) else {
+                        debug!("recv conn msg {} from {} for no frontend", msg, raddr);
+                        continue;
+                    };
+                    info!(
+                        fqdn = %msg.fqdn,
+                        peer = %msg.raddr,
+                        backend = %raddr,
+                        "backend ready"
+                    );
+                    let mut msg_reply = ConnMessage::new(Kind::Backend, raddr, msg.fqdn);
+                    msg_reply.candidates = msg.candidates;
+                    let data = capped(msg_reply, self.limits.max_response);
+                    let res = match reply {
+                        Route::Direct(frontend) => send(&socket, data, frontend).await,
+                        Route::Via(peer, frontend) => {
+                            let data = data.and_then(|data| {
+                                let mut msg = PeerMessage::new(PeerKind::Deliver, frontend);
+                                msg.payload = data;
+                                msg.seal(&peer_key)
+                            });
+                            send(&socket, data, net::reachable_from(&local, peer)).await
+                        }
+                    };
+                    if let Err(err) = res {
+                        warn!("send udp conn message err:{}", err)
+                    }
+                }
+                Message::Peer(_) => {
+                    if !peers.contains(&raddr) {
+                        warn!("recv peer msg from unknown server {}", raddr);
+                        continue;
+                    }
+                    let msg = match PeerMessage::open(&buf[..n], &peer_key) {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            warn!("recv peer msg from {}: {}", raddr, err);
+                            continue;
                         }
+                    };
+                    if now.as_secs().abs_diff(msg.sent) > PEER_MAX_AGE.as_secs() {
+                        warn!("recv stale peer msg {} from {}", msg, raddr);
+                        continue;
+                    }
+                    let res = self.peer_message(&socket, msg, raddr, now, &peer_key).await;
+                    if let Err(err) = res {
+                        warn!("peer message from {} error: {}", raddr, err);
                     }
                 }
+                Message::Unknown(data) => {
+                    debug!("recv unknown msg {:?} from {}", data, raddr);
+                }
+            }
+        }
+    }
+
+    /// Ask the backend of a frontend's request for a fresh punching socket,
+    /// or pass the request to the cluster member it is registered with.
+    #[instrument(skip_all, fields(fqdn = %msg.fqdn, peer = %raddr))]
+    async fn frontend_request(
+        &self,
+        socket: &UdpSocket,
+        msg: StunMessage,
+        raddr: SocketAddr,
+        reply: Route,
+        now: Duration,
+        peer_key: &hmac::Key,
+    ) -> Result<(), Box<dyn Error>> {
+        let local = socket.local_addr()?;
+        let fqdn = msg.fqdn.clone();
+        // relayed requests are only served by local backends so they can
+        // not bounce between members
+        let local_only = matches!(reply, Route::Via(_, _));
+        let identity = Identity::new(raddr.ip(), &msg.token);
+        let Some(notify) = self.connect_request(&fqdn, &identity, raddr, reply, local_only, now)
+        else {
+            return Ok(());
+        };
+        let (data, to) = match notify {
+            Notify::Member(peer) => {
+                let mut relay = PeerMessage::new(PeerKind::Forward, raddr);
+                relay.payload = msg.encode()?;
+                (relay.seal(peer_key)?, peer)
+            }
+            Notify::Backend(baddr, nonce) => {
+                let mut req = ConnMessage::new(Kind::Frontend, raddr, fqdn);
+                req.candidates = msg.candidates;
+                req.token = msg.token;
+                req.nonce = nonce;
+                (capped(req, self.limits.max_response)?, baddr)
+            }
+        };
+        if let Err(err) = socket.send_to(&data, net::reachable_from(&local, to)).await {
+            warn!("send udp conn message err:{}", err)
+        }
+        Ok(())
+    }
+
+    /// Hand a frontend or backend a cookie for its address instead of
+    /// acting on its request. The challenge is never larger than the
+    /// request, so it is useless for reflection.
+    async fn challenge(
+        &self,
+        socket: &UdpSocket,
+        msg: &StunMessage,
+        from: SocketAddr,
+        size: usize,
+    ) {
+        let mut reply = StunMessage::new(Kind::Stun, msg.fqdn.clone());
+        reply.cookie = self.cookies.issue(&net::canonical(from));
+        let data = match reply.encode() {
+            Ok(data) if data.len() <= size => data,
+            Ok(_) => {
+                debug!("request from {} too short for a cookie", from);
+                return;
+            }
+            Err(err) => {
+                warn!("encode cookie error: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = socket.send_to(&data, from).await {
+            warn!("send cookie to {} error: {}", from, err);
+        }
+    }
+
+    async fn peer_message(
+        &self,
+        socket: &UdpSocket,
+        msg: PeerMessage,
+        peer: SocketAddr,
+        now: Duration,
+        peer_key: &hmac::Key,
+    ) -> Result<(), Box<dyn Error>> {
+        let local = socket.local_addr()?;
+        match msg.kind {
+            PeerKind::Sync => {
+                let mut registry = self.registry.lock().unwrap();
+                for entry in msg.entries {
+                    let seen = now.saturating_sub(Duration::from_secs(entry.age as u64));
+                    registry.add_remote(entry.fqdn, net::canonical(entry.addr), seen, peer);
+                }
+            }
+            PeerKind::Forward => match message::decode(&msg.payload) {
+                Message::Stun(req) if req.kind == Kind::Frontend => {
+                    let frontend = net::canonical(msg.addr);
+                    debug!(fqdn = %req.fqdn, peer = %frontend, via = %peer, "recv from frontend");
+                    let reply = Route::Via(peer, frontend);
+                    self.frontend_request(socket, req, frontend, reply, now, peer_key)
+                        .await?;
+                }
+                _ => warn!("recv bad forward from {}", peer),
+            },
+            PeerKind::Deliver => {
+                let to = net::reachable_from(&local, msg.addr);
+                if let Err(err) = socket.send_to(&msg.payload, to).await {
+                    warn!("send udp conn message err:{}", err)
+                }
+            }
+            PeerKind::Unknown => {}
+        }
+        Ok(())
+    }
+
+    /// Send the registrations made with this server to every peer.
+    async fn sync(
+        socket: &UdpSocket,
+        registry: &Mutex<Registry>,
+        peers: &[SocketAddr],
+        peer_key: &hmac::Key,
+    ) -> Result<(), Box<dyn Error>> {
+        let local = socket.local_addr()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let entries = registry.lock().unwrap().local_entries(now);
+
+        let mut chunks = Vec::new();
+        let mut chunk = Vec::new();
+        let mut size = 0;
+        for entry in entries {
+            let entry_size = 1 + entry.fqdn.len() + 19 + 4;
+            if size + entry_size > SYNC_CHUNK && !chunk.is_empty() {
+                chunks.push(std::mem::take(&mut chunk));
+                size = 0;
             }
+            size += entry_size;
+            chunk.push(entry);
+        }
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+
+        for entries in chunks {
+            let mut msg = PeerMessage::new(PeerKind::Sync, local);
+            msg.entries = entries;
+            let data = msg.seal(peer_key)?;
+            for peer in peers {
+                if let Err(err) = socket
+                    .send_to(&data, net::reachable_from(&local, *peer))
+                    .await
+                {
+                    warn!("send sync to {} error: {}", peer, err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a frontend's request for `fqdn` and pick the backend to ask
+    /// for a punching socket, or the cluster member it is registered with,
+    /// if any may serve it.
+    fn connect_request(
+        &self,
+        fqdn: &str,
+        identity: &Identity,
+        raddr: SocketAddr,
+        reply: Route,
+        local_only: bool,
+        now: Duration,
+    ) -> Option<Notify> {
+        let mut registry = self.registry.lock().unwrap();
+        registry.expire_pending(now);
+        if registry.is_banned(fqdn, &raddr) {
+            registry.start_attempt(fqdn, raddr, None, now, Outcome::Banned);
+            info!("banned");
+            return None;
+        }
+        if !registry.acl.admits(fqdn, identity) {
+            registry.start_attempt(fqdn, raddr, None, now, Outcome::Denied);
+            info!("denied by acl");
+            return None;
+        }
+        if !self.per_fqdn.lock().unwrap().allow(fqdn.to_string()) {
+            registry.start_attempt(fqdn, raddr, None, now, Outcome::Limited);
+            info!("rate limited");
+            return None;
+        }
+        let Some((baddr, via)) = registry.get_backend(fqdn.to_string(), now, local_only) else {
+            registry.start_attempt(fqdn, raddr, None, now, Outcome::NoBackend);
+            info!("no backend");
+            return None;
+        };
+        if let Some(peer) = via {
+            registry.start_attempt(fqdn, raddr, Some(baddr), now, Outcome::Forwarded);
+            return Some(Notify::Member(peer));
+        }
+        // a repeated request keeps the attempt and nonce it started with
+        let (attempt, nonce) = match registry.pending.get(&raddr) {
+            Some(p) if p.fqdn == fqdn => (p.attempt, p.nonce.clone()),
+            _ => {
+                let attempt =
+                    registry.start_attempt(fqdn, raddr, Some(baddr), now, Outcome::Notified);
+                (attempt, new_nonce())
+            }
+        };
+        let pending = Pending {
+            fqdn: fqdn.to_string(),
+            reply,
+            seen: now,
+            attempt,
+            nonce: nonce.clone(),
+        };
+        registry.pending.insert(raddr, pending);
+        Some(Notify::Backend(baddr, nonce))
+    }
+
+    /// Where to send the answer for the frontend a backend's punching
+    /// socket is for. Only the backend that was asked knows the nonce of
+    /// the request, anyone else answering for the frontend is ignored. The
+    /// request is kept until it expires since the backend repeats this
+    /// until the frontend's punch gets through.
+    fn backend_ready(&self, msg: &ConnMessage) -> Option<Route> {
+        let mut registry = self.registry.lock().unwrap();
+        let pending = registry.pending.get(&net::canonical(msg.raddr))?;
+        if pending.fqdn != msg.fqdn || verify_slices_are_equal(&pending.nonce, &msg.nonce).is_err()
+        {
+            return None;
+        }
+        let (reply, attempt) = (pending.reply, pending.attempt);
+        registry.set_outcome(attempt, Outcome::Ready);
+        Some(reply)
+    }
+}
+
+/// A fresh nonce for a connect request.
+fn new_nonce() -> Vec<u8> {
+    let mut nonce = vec![0; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .expect("system random source");
+    nonce
+}
+
+/// Send an encoded message, so a message that fails to encode or a
+/// destination that can not be reached only loses that one message.
+async fn send(
+    socket: &UdpSocket,
+    data: Result<Vec<u8>, message::FmtError>,
+    to: SocketAddr,
+) -> Result<(), Box<dyn Error>> {
+    socket.send_to(&data?, to).await?;
+    Ok(())
+}
+
+/// Encode `msg`, leaving out candidates until it fits in `max` bytes.
+fn capped(mut msg: ConnMessage, max: usize) -> Result<Vec<u8>, message::FmtError> {
+    loop {
+        let data = msg.clone().encode()?;
+        if data.len() <= max || msg.candidates.pop().is_none() {
+            return Ok(data);
         }
     }
 }
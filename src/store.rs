@@ -0,0 +1,66 @@
+//! On-disk snapshot of the rendezvous registry so a restarted server can
+//! broker connections before every backend has registered again.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// A backend registration as stored on disk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredBackend {
+    pub fqdn: String,
+    pub addr: String,
+    /// Unix time of the last registration or keepalive in seconds.
+    pub seen: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    #[serde(default)]
+    pub backends: Vec<StoredBackend>,
+    #[serde(default)]
+    pub banned_fqdns: Vec<String>,
+    #[serde(default)]
+    pub banned_ips: Vec<IpAddr>,
+    /// Acl rules per fqdn, see [`Acl::from_rules`](crate::acl::Acl::from_rules).
+    #[serde(default)]
+    pub acl: HashMap<String, Vec<String>>,
+}
+
+/// A json snapshot file, replaced atomically on every save.
+pub struct Store {
+    path: PathBuf,
+}
+
+impl Store {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Store { path: path.into() }
+    }
+
+    /// Read the snapshot, an empty one if the file does not exist yet.
+    pub fn load(&self) -> io::Result<Snapshot> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Snapshot::default()),
+            Err(err) => return Err(err),
+        };
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Write the snapshot next to the file and move it in place once it
+    /// is on disk, so a crash leaves either the old or the new snapshot.
+    /// Blocks, call it off the runtime.
+    pub fn save(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(snapshot)?;
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
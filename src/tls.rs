@@ -1,10 +1,18 @@
+/// Application protocol of frontends and backends that start each forward
+/// stream with a [`StreamHeader`](crate::layer::iobound::header::StreamHeader).
+/// Older peers only know `h3`, and get streams without one.
+pub const ALPN_STREAM_HEADER: &[u8] = b"nnat/1";
+
+/// Application protocols offered between peers, most preferred first.
+pub const ALPN: [&[u8]; 2] = [ALPN_STREAM_HEADER, b"h3"];
+
 #[cfg(target_family = "windows")]
 pub mod rustls {
     use s2n_quic::provider::tls::default::rustls::client::{
         ServerCertVerified, ServerCertVerifier,
     };
-    use s2n_quic::provider::tls::default::rustls::ClientConfig;
-    use s2n_quic::provider::tls::default::rustls::{Certificate, ServerName};
+    use s2n_quic::provider::tls::default::rustls::{Certificate, PrivateKey, ServerName};
+    use s2n_quic::provider::tls::default::rustls::{ClientConfig, RootCertStore};
     use s2n_quic::provider::tls::default::Client;
     use std::error::Error;
     use std::sync::Arc;
@@ -34,10 +42,56 @@ pub mod rustls {
             .with_no_client_auth();
 
         cb.dangerous().set_certificate_verifier(verifier);
+        cb.alpn_protocols = super::ALPN.iter().map(|p| p.to_vec()).collect();
 
         let tls = Client::new(cb);
         return Ok(tls);
     }
+
+    /// Trust servers whose certificate chains to `cert` and is issued for
+    /// the server name given when connecting.
+    pub fn client_tls(cert: &str) -> Result<Client, Box<dyn Error>> {
+        let mut roots = RootCertStore::empty();
+        for der in super::load_certs(cert)? {
+            roots.add(&Certificate(der))?;
+        }
+        let mut cb = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        cb.alpn_protocols = super::ALPN.iter().map(|p| p.to_vec()).collect();
+
+        Ok(Client::new(cb))
+    }
+
+    /// Like [`insecure_client_tls`], presenting `identity_cert` to servers
+    /// that ask for a client certificate.
+    pub fn identity_client_tls(
+        _: &str,
+        identity_cert: &str,
+        identity_key: &str,
+    ) -> Result<Client, Box<dyn Error>> {
+        let certs = super::load_certs(identity_cert)?;
+        let key = super::load_key(identity_key)?;
+        let verifier = Arc::new(NoCertVerifier {});
+        let mut cb = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()?
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_client_auth_cert(
+                certs.into_iter().map(Certificate).collect(),
+                PrivateKey(key),
+            )?;
+
+        cb.dangerous().set_certificate_verifier(verifier);
+        cb.alpn_protocols = super::ALPN.iter().map(|p| p.to_vec()).collect();
+
+        let tls = Client::new(cb);
+        Ok(tls)
+    }
 }
 
 #[cfg(target_family = "unix")]
@@ -57,8 +111,124 @@ pub mod s2ntls {
         let tls = s2n_quic::provider::tls::default::Client::builder()
             .with_certificate(Path::new(cert))?
             .with_verify_host_name_callback(InsecureTls {})?
+            .with_application_protocols(super::ALPN)?
             .build()?;
 
         return Ok(tls);
     }
+
+    /// Trust servers whose certificate chains to `cert` and is issued for
+    /// the server name given when connecting.
+    pub fn client_tls(cert: &str) -> Result<Client, Box<dyn Error>> {
+        let tls = s2n_quic::provider::tls::default::Client::builder()
+            .with_certificate(Path::new(cert))?
+            .with_application_protocols(super::ALPN)?
+            .build()?;
+
+        Ok(tls)
+    }
+
+    /// Like [`insecure_client_tls`], presenting `identity_cert` to servers
+    /// that ask for a client certificate.
+    pub fn identity_client_tls(
+        cert: &str,
+        identity_cert: &str,
+        identity_key: &str,
+    ) -> Result<Client, Box<dyn Error>> {
+        let tls = s2n_quic::provider::tls::default::Client::builder()
+            .with_certificate(Path::new(cert))?
+            .with_client_identity(Path::new(identity_cert), Path::new(identity_key))?
+            .with_verify_host_name_callback(InsecureTls {})?
+            .with_application_protocols(super::ALPN)?
+            .build()?;
+
+        Ok(tls)
+    }
+}
+
+/// Server side of mutual TLS for the backend: any client certificate is
+/// accepted and its fingerprint recorded, access is decided by the acl.
+pub mod mtls {
+    use rustls021 as rustls;
+    use rustls021::server::{ClientCertVerified, ClientCertVerifier};
+    use rustls021::{Certificate, DistinguishedName, PrivateKey, ServerConfig};
+    use s2n_quic::provider::tls::rustls::Server;
+    use std::error::Error;
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+
+    use crate::acl;
+
+    /// Where the fingerprint of the client certificate ends up.
+    pub type Seen = Arc<Mutex<Option<String>>>;
+
+    struct RecordClientCert {
+        seen: Seen,
+    }
+
+    impl ClientCertVerifier for RecordClientCert {
+        fn client_auth_mandatory(&self) -> bool {
+            false
+        }
+
+        fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+            &[]
+        }
+
+        fn verify_client_cert(
+            &self,
+            end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _now: SystemTime,
+        ) -> Result<ClientCertVerified, rustls::Error> {
+            *self.seen.lock().unwrap() = Some(acl::fingerprint(&end_entity.0));
+            Ok(ClientCertVerified::assertion())
+        }
+    }
+
+    pub fn fingerprint_server_tls(
+        cert: &str,
+        key: &str,
+        seen: Seen,
+    ) -> Result<Server, Box<dyn Error>> {
+        let certs = super::load_certs(cert)?;
+        let key = super::load_key(key)?;
+        let mut config = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(Arc::new(RecordClientCert { seen }))
+            .with_single_cert(
+                certs.into_iter().map(Certificate).collect(),
+                PrivateKey(key),
+            )?;
+        // what the s2n-quic builders set up as well, plus our protocol
+        config.ignore_client_order = true;
+        config.max_fragment_size = None;
+        config.alpn_protocols = super::ALPN.iter().map(|p| p.to_vec()).collect();
+        Ok(Server::from(config))
+    }
+}
+
+/// DER certificates of the pem file at `path`.
+fn load_certs(path: &str) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut rd = std::io::BufReader::new(std::fs::File::open(path)?);
+    rustls_pemfile::certs(&mut rd)
+}
+
+/// The first private key of the pem file at `path`.
+fn load_key(path: &str) -> std::io::Result<Vec<u8>> {
+    let mut rd = std::io::BufReader::new(std::fs::File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut rd)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(key),
+            _ => {}
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("no private key in {}", path),
+    ))
 }
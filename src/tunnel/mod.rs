@@ -1,45 +1,137 @@
+pub mod proxy_protocol;
+pub mod udp;
+
+use std::collections::HashMap;
 use std::{error::Error, net::SocketAddr};
 
 use s2n_quic::stream::BidirectionalStream;
-use tokio::net::{TcpSocket, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::debug;
 
-pub async fn forward_tunnel(
-    mut tcp_stream: TcpStream,
-    mut quic_stream: BidirectionalStream,
-) -> Result<(), Box<dyn Error>> {
-    // let mut quic_stream = quic_conn.open_bidirectional_stream().await?;
-    let (from_client, from_server) =
-        tokio::io::copy_bidirectional(&mut tcp_stream, &mut quic_stream).await?;
+use crate::layer::iobound::compress::{Codec, Compressed};
+use crate::layer::iobound::header::StreamHeader;
+use crate::layer::iobound::io::BiStream;
+use crate::layer::iobound::shape::{Limit, Shaper};
+use crate::layer::iobound::target::Target;
+use crate::layer::iobound::tunnel::{Timeouts, Tunnel};
+use crate::{metrics, net};
 
-    println!(
-        "client wrote {} bytes and received {} bytes",
-        from_client, from_server
-    );
+/// Carry a local tcp connection over a stream the peer serves; traffic is
+/// counted toward `service`.
+pub async fn forward_tunnel<S>(
+    service: &str,
+    tcp_stream: TcpStream,
+    quic_stream: S,
+    timeouts: Timeouts,
+    limit: Limit,
+) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // let mut quic_stream = quic_conn.open_bidirectional_stream().await?;
+    let peer = tcp_stream.peer_addr().ok();
+    let mut tunnel = Tunnel::labeled(
+        BiStream::new(tcp_stream),
+        BiStream::new(quic_stream),
+        service,
+        peer,
+    )
+    .with_timeouts(timeouts)
+    .with_limit(limit);
+    let res = tunnel.copy().await;
+    finished("forward", service, &tunnel);
+    res?;
     Ok(())
 }
 
+/// Connect a stream the peer opened to the tcp service at `raddr`, giving
+/// up after `timeouts.connect`; traffic is counted toward `service`. The
+/// stream header names the client behind the frontend, which is announced
+/// to the service with a PROXY protocol header of `proxy` if set. Without
+/// `headers` the frontend predates stream headers and the stream carries
+/// the client's bytes from the start.
 pub async fn backward_tunnel(
+    service: &str,
     raddr: SocketAddr,
-    mut quic_stream: BidirectionalStream,
+    quic_stream: BidirectionalStream,
+    timeouts: Timeouts,
+    limit: Limit,
+    proxy: Option<proxy_protocol::Version>,
+    headers: bool,
 ) -> Result<(), Box<dyn Error>> {
-    let mut tcp_stream: TcpStream;
-    match raddr {
-        SocketAddr::V4(raddr) => {
-            let tcp_socket = TcpSocket::new_v4()?;
-            tcp_stream = tcp_socket.connect(raddr.into()).await?;
-        }
-        SocketAddr::V6(raddr) => {
-            let tcp_socket = TcpSocket::new_v6()?;
-            tcp_stream = tcp_socket.connect(raddr.into()).await?;
-        }
+    let (header, quic_stream) = match headers {
+        true => Compressed::accept(quic_stream, Codec::ALL).await?,
+        false => (StreamHeader::new(), Compressed::Raw(quic_stream)),
+    };
+    // the stream a frontend sends its token on carries nothing else
+    if header.token.is_some() {
+        return Ok(());
+    }
+    let mut tcp_stream = timeouts
+        .connect(net::tcp_socket(&raddr)?.connect(raddr))
+        .await?;
+    if let Some(version) = proxy {
+        let buf = proxy_protocol::header(version, header.client, raddr);
+        tcp_stream.write_all(&buf).await?;
     }
 
-    let (from_client, from_server) =
-        tokio::io::copy_bidirectional(&mut quic_stream, &mut tcp_stream).await?;
+    let mut tunnel = Tunnel::labeled(
+        BiStream::new(quic_stream),
+        BiStream::new(tcp_stream),
+        service,
+        header.client,
+    )
+    .with_timeouts(timeouts)
+    .with_limit(limit);
+    let res = tunnel.copy().await;
+    finished("backward", service, &tunnel);
+    res?;
+    Ok(())
+}
+
+/// Serve a stream the peer opened toward a service on this side of the
+/// connection. The stream header names the service; only names found in
+/// `services` are connected, limited by the shaper's limit for the service
+/// and decompressed if the header offers a codec.
+pub async fn service_tunnel(
+    services: &HashMap<String, Target>,
+    quic_stream: BidirectionalStream,
+    timeouts: Timeouts,
+    shaper: &Shaper,
+) -> Result<(), Box<dyn Error>> {
+    let (header, quic_stream) = Compressed::accept(quic_stream, Codec::ALL).await?;
+    let service = header.service.unwrap_or_default();
+    let target = match services.get(&service) {
+        Some(target) => target,
+        None => return Err(format!("unknown service {:?}", service).into()),
+    };
+    let tcp_stream = timeouts.connect(target.connect()).await?;
 
-    println!(
-        "client wrote {} bytes and received {} bytes",
-        from_client, from_server
-    );
+    let mut tunnel = Tunnel::labeled(
+        BiStream::new(quic_stream),
+        BiStream::new(tcp_stream),
+        &service,
+        None,
+    )
+    .with_timeouts(timeouts)
+    .with_limit(shaper.limit(&service, None));
+    let res = tunnel.copy().await;
+    finished("service", &service, &tunnel);
+    res?;
     Ok(())
 }
+
+/// Count what a closed tunnel carried toward `service`, including tunnels
+/// cut by a timeout, and log why it closed.
+fn finished<I, O>(kind: &str, service: &str, tunnel: &Tunnel<I, O>)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    O: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let stats = tunnel.stats();
+    let (sent, received) = (stats.sent_bytes(), stats.received_bytes());
+    metrics::global().add_bytes(service, sent, received);
+    let reason = tunnel.close_reason().map(|c| c.name()).unwrap_or_default();
+    debug!(service, sent, received, reason, "{} tunnel closed", kind);
+}
@@ -0,0 +1,165 @@
+//! HAProxy PROXY protocol headers, prepended to the connection toward a
+//! local service so it sees the address of the client behind the frontend
+//! instead of the backend's.
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::net::{IpAddr, SocketAddr};
+
+use serde::Deserialize;
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_PROXY: u8 = 0x21;
+const V2_LOCAL: u8 = 0x20;
+const TCP4: u8 = 0x11;
+const TCP6: u8 = 0x21;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    /// The human readable text header.
+    V1,
+    /// The binary header.
+    V2,
+}
+
+/// Both addresses in the same family, IPv4 ones mapped into IPv6 if the
+/// other is IPv6.
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    if src.is_ipv4() == dst.is_ipv4() {
+        (src, dst)
+    } else {
+        (v6(src), v6(dst))
+    }
+}
+
+/// The header announcing a connection from `src` to `dst`. Without a
+/// known `src` the header says so and the service keeps the real peer.
+pub fn header(version: Version, src: Option<SocketAddr>, dst: SocketAddr) -> Vec<u8> {
+    match version {
+        Version::V1 => v1(src, dst).into_bytes(),
+        Version::V2 => v2(src, dst),
+    }
+}
+
+fn v1(src: Option<SocketAddr>, dst: SocketAddr) -> String {
+    let Some(src) = src else {
+        return "PROXY UNKNOWN\r\n".to_string();
+    };
+    let (src, dst) = same_family(src, dst);
+    let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        src.ip(),
+        dst.ip(),
+        src.port(),
+        dst.port()
+    )
+}
+
+fn v2(src: Option<SocketAddr>, dst: SocketAddr) -> Vec<u8> {
+    let mut buf = SIGNATURE.to_vec();
+    let Some(src) = src else {
+        buf.extend([V2_LOCAL, 0, 0, 0]);
+        return buf;
+    };
+    let mut addrs = Vec::with_capacity(36);
+    let family = match same_family(src, dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            addrs.extend(src.ip().octets());
+            addrs.extend(dst.ip().octets());
+            TCP4
+        }
+        (src, dst) => {
+            let ip6 = |addr: SocketAddr| match addr.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+                IpAddr::V6(ip) => ip.octets(),
+            };
+            addrs.extend(ip6(src));
+            addrs.extend(ip6(dst));
+            TCP6
+        }
+    };
+    addrs.extend(src.port().to_be_bytes());
+    addrs.extend(dst.port().to_be_bytes());
+    buf.push(V2_PROXY);
+    buf.push(family);
+    buf.extend((addrs.len() as u16).to_be_bytes());
+    buf.extend(addrs);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let buf = header(
+            Version::V1,
+            Some(addr("192.0.2.1:5000")),
+            addr("10.0.0.1:80"),
+        );
+        assert_eq!(buf, b"PROXY TCP4 192.0.2.1 10.0.0.1 5000 80\r\n");
+    }
+
+    #[test]
+    fn v1_tcp6_maps_v4() {
+        let buf = header(Version::V1, Some(addr("192.0.2.1:5000")), addr("[::1]:80"));
+        assert_eq!(buf, b"PROXY TCP6 ::ffff:192.0.2.1 ::1 5000 80\r\n");
+    }
+
+    #[test]
+    fn v1_unknown() {
+        assert_eq!(
+            header(Version::V1, None, addr("10.0.0.1:80")),
+            b"PROXY UNKNOWN\r\n"
+        );
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        let buf = header(
+            Version::V2,
+            Some(addr("192.0.2.1:5000")),
+            addr("10.0.0.1:80"),
+        );
+        let mut want = SIGNATURE.to_vec();
+        want.extend([0x21, 0x11, 0, 12]);
+        want.extend([192, 0, 2, 1, 10, 0, 0, 1]);
+        want.extend([0x13, 0x88, 0, 80]);
+        assert_eq!(buf, want);
+    }
+
+    #[test]
+    fn v2_tcp6() {
+        let buf = header(
+            Version::V2,
+            Some(addr("[2001:db8::1]:443")),
+            addr("127.0.0.1:22"),
+        );
+        let mut want = SIGNATURE.to_vec();
+        want.extend([0x21, 0x21, 0, 36]);
+        want.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        want.extend([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 127, 0, 0, 1]);
+        want.extend([0x01, 0xbb, 0, 22]);
+        assert_eq!(buf, want);
+    }
+
+    #[test]
+    fn v2_local() {
+        let buf = header(Version::V2, None, addr("10.0.0.1:80"));
+        let mut want = SIGNATURE.to_vec();
+        want.extend([0x20, 0, 0, 0]);
+        assert_eq!(buf, want);
+    }
+}
@@ -0,0 +1,301 @@
+//! UDP forwarding over QUIC DATAGRAM frames.
+//!
+//! Every datagram is `flow id (u32) | payload`. The frontend gives each
+//! client address seen on its udp listener a flow id, the backend opens one
+//! udp socket toward the service per flow id so replies find their way
+//! back. Flows idle for longer than the idle timeout are dropped on both
+//! sides.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use s2n_quic::connection::Handle;
+use s2n_quic::provider::datagram::default::{DatagramError, Endpoint, Receiver, Sender};
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn, Instrument};
+
+use crate::net;
+
+/// How long a flow may go without traffic before it is forgotten.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Flows one frontend may have open on the backend by default.
+pub const MAX_FLOWS: usize = 1024;
+
+/// Datagrams queued per direction before the oldest ones are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+const MAX_DATAGRAM: usize = 65535;
+
+/// The datagram provider both ends of the QUIC connection must be built
+/// with for DATAGRAM frames to be negotiated.
+pub fn endpoint() -> Result<Endpoint, Box<dyn Error>> {
+    let endpoint = Endpoint::builder()
+        .with_send_capacity(QUEUE_CAPACITY)?
+        .with_recv_capacity(QUEUE_CAPACITY)?
+        .build()?;
+    Ok(endpoint)
+}
+
+/// Queue one datagram for `flow`. A full queue drops the oldest datagram
+/// and oversized payloads are dropped, as a udp path would.
+fn send(handle: &Handle, flow: u32, payload: &[u8]) -> io::Result<()> {
+    let mut data = BytesMut::with_capacity(4 + payload.len());
+    data.put_u32(flow);
+    data.put_slice(payload);
+    let res = handle
+        .datagram_mut(|sender: &mut Sender| sender.send_datagram_forced(data.freeze()))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    match res {
+        Ok(_) => Ok(()),
+        Err(DatagramError::ExceedsPeerTransportLimits { .. }) => {
+            debug!(flow, size = payload.len(), "drop datagram: too large");
+            Ok(())
+        }
+        Err(err) => Err(io::Error::other(err.to_string())),
+    }
+}
+
+/// Wait for the next datagram from the peer, skipping malformed ones.
+async fn recv(handle: &Handle) -> io::Result<(u32, Bytes)> {
+    loop {
+        let mut data = poll_fn(|cx| {
+            match handle.datagram_mut(|receiver: &mut Receiver| receiver.poll_recv_datagram(cx)) {
+                Ok(poll) => poll.map(|res| res.map_err(|e| io::Error::other(e.to_string()))),
+                Err(err) => Poll::Ready(Err(io::Error::other(err.to_string()))),
+            }
+        })
+        .await?;
+        if data.len() < 4 {
+            debug!("recv short datagram of {} bytes", data.len());
+            continue;
+        }
+        let flow = data.get_u32();
+        return Ok((flow, data));
+    }
+}
+
+/// Client addresses seen on the frontend listener and their flow ids.
+#[derive(Default)]
+struct Flows {
+    by_addr: HashMap<SocketAddr, u32>,
+    by_id: HashMap<u32, (SocketAddr, Instant)>,
+    next: u32,
+}
+
+impl Flows {
+    fn id_for(&mut self, addr: SocketAddr) -> u32 {
+        let now = Instant::now();
+        if let Some(id) = self.by_addr.get(&addr) {
+            self.by_id.insert(*id, (addr, now));
+            return *id;
+        }
+        while self.by_id.contains_key(&self.next) {
+            self.next = self.next.wrapping_add(1);
+        }
+        let id = self.next;
+        self.next = self.next.wrapping_add(1);
+        self.by_addr.insert(addr, id);
+        self.by_id.insert(id, (addr, now));
+        id
+    }
+
+    fn addr_of(&mut self, id: u32) -> Option<SocketAddr> {
+        let (addr, seen) = self.by_id.get_mut(&id)?;
+        *seen = Instant::now();
+        Some(*addr)
+    }
+
+    fn expire(&mut self, idle: Duration) {
+        let by_addr = &mut self.by_addr;
+        self.by_id.retain(|id, (addr, seen)| {
+            if seen.elapsed() < idle {
+                return true;
+            }
+            debug!(flow = id, %addr, "udp flow expired");
+            by_addr.remove(addr);
+            false
+        });
+    }
+}
+
+/// Listen for udp on `laddr` and carry every client's datagrams over the
+/// QUIC connection, returning once the connection is gone.
+pub async fn forward_datagrams(
+    laddr: SocketAddr,
+    handle: Handle,
+    idle: Duration,
+) -> io::Result<()> {
+    let socket = net::udp_bind(laddr)?;
+    let flows = Mutex::new(Flows::default());
+    info!("udp forward listen on {}", laddr);
+
+    let outgoing = async {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut ticker = tokio::time::interval(idle / 2);
+        loop {
+            tokio::select! {
+                res = socket.recv_from(&mut buf) => {
+                    let (n, raddr) = match res {
+                        Ok(res) => res,
+                        Err(err) => {
+                            // e.g. an icmp error for an earlier reply
+                            debug!("udp recv error: {}", err);
+                            continue;
+                        }
+                    };
+                    let flow = flows.lock().unwrap().id_for(raddr);
+                    send(&handle, flow, &buf[..n])?;
+                }
+                _ = ticker.tick() => flows.lock().unwrap().expire(idle),
+            }
+        }
+    };
+    let incoming = async {
+        loop {
+            let (flow, data) = recv(&handle).await?;
+            let addr = flows.lock().unwrap().addr_of(flow);
+            match addr {
+                Some(addr) => {
+                    if let Err(err) = socket.send_to(&data, addr).await {
+                        warn!("udp send to {} error: {}", addr, err);
+                    }
+                }
+                None => debug!(flow, "drop datagram for unknown flow"),
+            }
+        }
+    };
+    tokio::select! {
+        res = outgoing => res,
+        res = incoming => res,
+    }
+}
+
+/// A backend udp socket connected to the service for one flow.
+struct Session {
+    socket: UdpSocket,
+    seen: Mutex<Instant>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.seen.lock().unwrap() = Instant::now();
+    }
+
+    fn idle(&self) -> Duration {
+        self.seen.lock().unwrap().elapsed()
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<u32, Arc<Session>>>>;
+
+/// Deliver datagrams from the QUIC connection to the udp service at
+/// `raddr`, one socket per flow and at most `max_flows` of them, returning
+/// once the connection is gone. Datagrams that would start a flow beyond
+/// the limit, or whose socket can not be opened, are dropped.
+pub async fn backward_datagrams(
+    raddr: SocketAddr,
+    handle: Handle,
+    idle: Duration,
+    max_flows: usize,
+) -> io::Result<()> {
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+    loop {
+        let (flow, data) = recv(&handle).await?;
+        let session = sessions.lock().unwrap().get(&flow).cloned();
+        let session = match session {
+            Some(session) => session,
+            None => {
+                if sessions.lock().unwrap().len() >= max_flows {
+                    debug!(flow, "drop datagram: too many udp flows");
+                    continue;
+                }
+                let socket = match open(raddr).await {
+                    Ok(socket) => socket,
+                    Err(err) => {
+                        warn!(flow, "udp flow to {} error: {}", raddr, err);
+                        continue;
+                    }
+                };
+                let session = Arc::new(Session {
+                    socket,
+                    seen: Mutex::new(Instant::now()),
+                });
+                sessions.lock().unwrap().insert(flow, session.clone());
+                debug!(flow, %raddr, "udp flow started");
+                tokio::spawn(
+                    reply(
+                        flow,
+                        session.clone(),
+                        sessions.clone(),
+                        handle.clone(),
+                        idle,
+                    )
+                    .in_current_span(),
+                );
+                session
+            }
+        };
+        session.touch();
+        if let Err(err) = session.socket.send(&data).await {
+            warn!("udp send to {} error: {}", raddr, err);
+        }
+    }
+}
+
+/// A socket connected to the service at `raddr` for a new flow.
+async fn open(raddr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = net::udp_bind_any()?;
+    socket
+        .connect(net::reachable_from(&socket.local_addr()?, raddr))
+        .await?;
+    Ok(socket)
+}
+
+/// Carry the service's replies for `flow` back until the flow goes idle
+/// or the connection is gone.
+async fn reply(
+    flow: u32,
+    session: Arc<Session>,
+    sessions: Sessions,
+    handle: Handle,
+    idle: Duration,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        match tokio::time::timeout(idle, session.socket.recv(&mut buf)).await {
+            Ok(Ok(n)) => {
+                session.touch();
+                if let Err(err) = send(&handle, flow, &buf[..n]) {
+                    debug!(flow, "udp flow closed: {}", err);
+                    break;
+                }
+            }
+            // the service is not listening (yet), keep the flow
+            Ok(Err(err)) if err.kind() == io::ErrorKind::ConnectionRefused => {}
+            Ok(Err(err)) => {
+                warn!(flow, "udp flow recv error: {}", err);
+                break;
+            }
+            Err(_) => {
+                if session.idle() >= idle {
+                    debug!(flow, "udp flow expired");
+                    break;
+                }
+            }
+        }
+    }
+    let mut sessions = sessions.lock().unwrap();
+    if let Some(current) = sessions.get(&flow) {
+        if Arc::ptr_eq(current, &session) {
+            sessions.remove(&flow);
+        }
+    }
+}